@@ -1,52 +1,54 @@
-use anyhow::bail;
+use num_bigint::BigInt;
 
+use crate::constfold::fold_constants;
+use crate::error::SblError;
 use crate::numeric_litteral::NumericLiteral;
+use crate::span::{Span, Spanned};
 use crate::tokenizer::Token;
-use std::slice::Iter;
-use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum TopLevelNode {
-    WordDeclare(String, ASTNode),       // @ident {expr}
-    Typing(String, Vec<TypingASTNode>), // ?ident type
+    WordDeclare(String, Spanned<ASTNode>), // @ident {expr}
+    Typing(String, Vec<TypingASTNode>),    // ?ident type
+    Use(String),                           // use "path/to/module.sbl"
 }
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ASTNode {
-    Curly(Vec<ASTNode>),
-    Square(Vec<ASTNode>),
+    Curly(Vec<Spanned<ASTNode>>),
+    Square(Vec<Spanned<ASTNode>>),
 
     Ident(String),
     NumericLiteral(NumericLiteral),
     StringLiteral(String),
 
-    Dec(String), // value ::variable | value ::CONST
+    Dec(String),                     // value ::variable | value ::CONST
+    DecTyped(String, TypeComponent), // value ::variable:type | value ::CONST:type
+    Assign(String),                  // value :variables
 
-    // TODO
     DecArraySized(String, NumericLiteral), // :#pointer[size]
-    DecTyped(String, TypeComponent),       // value ::variable:type | value ::CONST:type
     DecArrayVariable(String),              // size :#pointer[]
-    DecPointer(String),                    // value :#pointer
-    PointerAssign(String),                 // value $:#{single stack entry expression}
-    Assign(String),                        // value :variables
+    DecPointer(String),                    // pointer :#pointer
+    PointerAssign(String),                 // value $:#pointer
     IndexAssign(String),                   // value index $:pointer[]
+    ReadIndex(String),                     // index $pointer[]
     Address(String),                       // #pointerOrVariable
-    ReadAddress(String),                   // $pointerOrVariable
+    ReadAddress(String),                   // $pointer
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TypingASTNode {
     Push(TypeComponent),
     Pop(TypeComponent),
+    Match(TypeComponent, Vec<NumericLiteral>), // -a@(1 | 2)
+    Never,
 }
-#[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TypeComponent {
-    variable: Option<String>,
-    type_name_components: Vec<String>,
-    explicit: bool,
-    poly: bool,
+    pub variable: Option<String>,
+    pub type_name_components: Vec<String>,
+    pub explicit: bool,
+    pub poly: bool,
 }
 
 #[derive(Debug)]
@@ -62,99 +64,178 @@ pub enum FoldedStreamNode {
     AtSign,
     QMark,
 
-    Square(Vec<FoldedStreamNode>),
-    Curly(Vec<FoldedStreamNode>),
+    Square(Vec<Spanned<FoldedStreamNode>>),
+    Curly(Vec<Spanned<FoldedStreamNode>>),
 }
-fn fold_until_sqend(stream: &mut Iter<Token>) -> Vec<FoldedStreamNode> {
-    let mut out = Vec::new();
-
-    while let Some(x) = stream.next() {
-        use FoldedStreamNode::*;
-        out.push(match x {
-            Token::Square(true) => Square(fold_until_sqend(stream)),
-            Token::Curly(true) => Curly(fold_until_cuend(stream)),
-
-            Token::Square(false) => break,
-            Token::Curly(false) => panic!("missmatch perens"),
-
-            Token::Comment(_) => continue,
-            Token::Ident(x) => Ident(x.clone()),
-            Token::NumericLiteral(x) => NumericLiteral(x.clone()),
-            Token::CharLiteral(x) => CharLiteral(*x),
-            Token::StringLiteral(x) => StringLiteral(x.clone()),
-            Token::Dollar => Dollar,
-            Token::Colon => Colon,
-            Token::Octothorp => Octothorp,
-            Token::AtSign => AtSign,
-            Token::QMark => QMark,
-        })
+fn bracket(token: &Token) -> char {
+    match token {
+        Token::Square(true) => '[',
+        Token::Square(false) => ']',
+        Token::Curly(true) => '{',
+        _ => '}',
     }
+}
 
-    out
+fn describe(node: &FoldedStreamNode) -> String {
+    use FoldedStreamNode::*;
+    match node {
+        Ident(x) | NumericLiteral(x) => x.clone(),
+        CharLiteral(x) => format!("{:?}", x),
+        StringLiteral(x) => format!("{:?}", x),
+        Dollar => "$".to_string(),
+        Colon => ":".to_string(),
+        Octothorp => "#".to_string(),
+        AtSign => "@".to_string(),
+        QMark => "?".to_string(),
+        Square(_) => "[...]".to_string(),
+        Curly(_) => "{...}".to_string(),
+    }
 }
-fn fold_until_cuend(stream: &mut Iter<Token>) -> Vec<FoldedStreamNode> {
+
+type TokenStream = std::iter::Peekable<std::vec::IntoIter<Spanned<Token>>>;
+
+// Folds up to the bracket closing the innermost of `open` and returns the span of the closing
+// bracket. Bracket errors are collected and folding carries on: a closer of an outer bracket
+// ends the inner one as well, any other stray closer is skipped
+fn fold_until(
+    stream: &mut TokenStream,
+    open: &mut Vec<Spanned<Token>>,
+    errors: &mut Vec<SblError>,
+) -> (Vec<Spanned<FoldedStreamNode>>, Span) {
     let mut out = Vec::new();
 
-    while let Some(x) = stream.next() {
+    while let Some(Spanned { node: token, span }) = stream.peek() {
+        let span = *span;
+
+        if let Token::Square(false) | Token::Curly(false) = token {
+            let closes = |x: &Spanned<Token>| match x.node {
+                Token::Square(true) => *token == Token::Square(false),
+                _ => *token == Token::Curly(false),
+            };
+
+            match open.last() {
+                Some(x) if closes(x) => {
+                    stream.next();
+                    return (out, span);
+                }
+                Some(x) => {
+                    errors.push(SblError::MismatchedBracket {
+                        found: bracket(token),
+                        open: bracket(&x.node),
+                        opened_at: x.span,
+                        span,
+                    });
+
+                    if open.iter().any(closes) {
+                        return (out, span);
+                    }
+                }
+                None => errors.push(SblError::UnmatchedBracket(bracket(token), span)),
+            }
+
+            stream.next();
+            continue;
+        }
+
+        let token = match stream.next() {
+            Some(x) => x.node,
+            None => break,
+        };
+
         use FoldedStreamNode::*;
-        out.push(match x {
-            Token::Square(true) => Square(fold_until_sqend(stream)),
-            Token::Curly(true) => Curly(fold_until_cuend(stream)),
-
-            Token::Square(false) => panic!("missmatch perens"),
-            Token::Curly(false) => break,
-
-            Token::Comment(_) => continue,
-            Token::Ident(x) => Ident(x.clone()),
-            Token::NumericLiteral(x) => NumericLiteral(x.clone()),
-            Token::CharLiteral(x) => CharLiteral(x.clone()),
-            Token::StringLiteral(x) => StringLiteral(x.clone()),
+        let node = match token {
+            Token::Square(true) | Token::Curly(true) => {
+                open.push(Spanned::new(token.clone(), span));
+                let (content, end) = fold_until(stream, open, errors);
+                open.pop();
+
+                let node = if token == Token::Square(true) {
+                    Square(content)
+                } else {
+                    Curly(content)
+                };
+
+                out.push(Spanned::new(node, span.to(end)));
+                continue;
+            }
+
+            Token::Square(false) | Token::Curly(false) | Token::Comment(_) => continue,
+            Token::Ident(x) => Ident(x),
+            Token::NumericLiteral(x) => NumericLiteral(x),
+            Token::CharLiteral(x) => CharLiteral(x),
+            Token::StringLiteral(x) => StringLiteral(x),
             Token::Dollar => Dollar,
             Token::Colon => Colon,
             Token::Octothorp => Octothorp,
             Token::AtSign => AtSign,
             Token::QMark => QMark,
-        })
+        };
+
+        out.push(Spanned::new(node, span));
     }
 
-    out
+    match open.last() {
+        Some(x) => {
+            errors.push(SblError::UnclosedBracket(bracket(&x.node), x.span));
+            (out, x.span)
+        }
+        None => (out, Span::default()),
+    }
+}
+pub fn fold_stream(
+    stream: Vec<Spanned<Token>>,
+) -> Result<Vec<Spanned<FoldedStreamNode>>, SblError> {
+    let mut errors = Vec::new();
+    let (out, _) = fold_until(
+        &mut stream.into_iter().peekable(),
+        &mut Vec::new(),
+        &mut errors,
+    );
+
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(out),
+    }
 }
-pub fn fold_stream(stream: Vec<Token>) -> Vec<FoldedStreamNode> {
-    let mut out = Vec::new();
 
-    let mut stream = stream.iter();
+type FoldedStream = std::iter::Peekable<std::vec::IntoIter<Spanned<FoldedStreamNode>>>;
 
-    while let Some(x) = stream.next() {
-        use FoldedStreamNode::*;
-        out.push(match x {
-            Token::Square(true) => Square(fold_until_sqend(&mut stream)),
-            Token::Curly(true) => Curly(fold_until_cuend(&mut stream)),
-
-            Token::Square(false) => panic!("missmatch perens"),
-            Token::Curly(false) => panic!("missmatch perens"),
-
-            Token::Comment(_) => continue,
-            Token::Ident(x) => Ident(x.clone()),
-            Token::NumericLiteral(x) => NumericLiteral(x.clone()),
-            Token::CharLiteral(x) => CharLiteral(x.clone()),
-            Token::StringLiteral(x) => StringLiteral(x.clone()),
-            Token::Dollar => Dollar,
-            Token::Colon => Colon,
-            Token::Octothorp => Octothorp,
-            Token::AtSign => AtSign,
-            Token::QMark => QMark,
-        })
-    }
+fn next_is(stream: &mut FoldedStream, f: impl Fn(&FoldedStreamNode) -> bool) -> bool {
+    stream.next_if(|x| f(&x.node)).is_some()
+}
 
-    out
+fn ident(stream: &mut FoldedStream, span: Span) -> Result<(String, Span), SblError> {
+    match stream.next() {
+        Some(Spanned {
+            node: FoldedStreamNode::Ident(name),
+            span,
+        }) => Ok((name, span)),
+        Some(x) => Err(SblError::UnexpectedToken(describe(&x.node), x.span)),
+        None => Err(SblError::UnexpectedEof(span)),
+    }
 }
 
-type FoldedStream = std::iter::Peekable<std::vec::IntoIter<FoldedStreamNode>>;
+// A bracket directly after the ident, `p[]` as opposed to `p []`
+fn subscript(
+    stream: &mut FoldedStream,
+    ident: Span,
+) -> Option<Spanned<Vec<Spanned<FoldedStreamNode>>>> {
+    match stream
+        .next_if(|x| matches!(x.node, FoldedStreamNode::Square(_)) && x.span.start == ident.end)
+    {
+        Some(Spanned {
+            node: FoldedStreamNode::Square(content),
+            span,
+        }) => Some(Spanned::new(content, span)),
+        _ => None,
+    }
+}
 
 impl ASTNode {
-    pub fn new(node: &mut FoldedStream) -> anyhow::Result<ASTNode> {
-        if let Some(x) = node.next() {
-            Ok(match x {
+    pub fn new(node: &mut FoldedStream) -> Result<Spanned<ASTNode>, SblError> {
+        if let Some(Spanned { node: x, span }) = node.next() {
+            let mut span = span;
+            let x = match x {
                 FoldedStreamNode::Square(values) => {
                     let mut out = Vec::with_capacity(values.len());
 
@@ -180,78 +261,378 @@ impl ASTNode {
 
                 FoldedStreamNode::Ident(x) => ASTNode::Ident(x),
                 FoldedStreamNode::NumericLiteral(x) => {
-                    ASTNode::NumericLiteral(NumericLiteral::from_str(x.as_str())?)
+                    ASTNode::NumericLiteral(NumericLiteral::parse(x.as_str(), span)?)
                 }
 
                 FoldedStreamNode::CharLiteral(char) => {
-                    ASTNode::NumericLiteral(NumericLiteral::Uint(char as u8, 8))
+                    ASTNode::NumericLiteral(NumericLiteral::Uint(32, BigInt::from(char as u32)))
                 }
                 FoldedStreamNode::StringLiteral(s) => ASTNode::StringLiteral(s),
 
-                FoldedStreamNode::Dollar => todo!(),
-                FoldedStreamNode::Colon => match (node.next(), node.next()) {
-                    (Some(FoldedStreamNode::Colon), Some(FoldedStreamNode::Ident(name))) => {
-                        ASTNode::Dec(name)
+                // $p | index $p[] | value $:#p | value index $:p[]
+                FoldedStreamNode::Dollar => {
+                    let assign = next_is(node, |x| matches!(x, FoldedStreamNode::Colon));
+                    let through =
+                        assign && next_is(node, |x| matches!(x, FoldedStreamNode::Octothorp));
+
+                    let (name, end) = ident(node, span)?;
+                    let index = subscript(node, end);
+                    span = span.to(index.as_ref().map_or(end, |x| x.span));
+
+                    match (assign, through, index) {
+                        (false, _, None) => ASTNode::ReadAddress(name),
+                        (true, true, None) => ASTNode::PointerAssign(name),
+                        (false, _, Some(x)) if x.node.is_empty() => ASTNode::ReadIndex(name),
+                        (true, false, Some(x)) if x.node.is_empty() => ASTNode::IndexAssign(name),
+                        (_, _, Some(x)) => {
+                            return Err(SblError::UnexpectedToken("[...]".to_string(), x.span))
+                        }
+                        (true, false, None) => return Err(SblError::UnexpectedToken(name, end)),
                     }
-                    _ => bail!("Unexpected token or EOF"),
-                },
-                FoldedStreamNode::Octothorp => todo!(),
-                FoldedStreamNode::AtSign => todo!(),
-                FoldedStreamNode::QMark => todo!(),
-            })
+                }
+                // ::x | ::x:type | :x | :#p | :#p[size] | :#p[]
+                FoldedStreamNode::Colon => {
+                    let declare = next_is(node, |x| matches!(x, FoldedStreamNode::Colon));
+                    let pointer =
+                        !declare && next_is(node, |x| matches!(x, FoldedStreamNode::Octothorp));
+
+                    let (name, end) = ident(node, span)?;
+                    span = span.to(end);
+
+                    if pointer {
+                        let index = subscript(node, end);
+                        if let Some(x) = &index {
+                            span = span.to(x.span);
+                        }
+
+                        match index.map(|x| (x.node, x.span)) {
+                            None => ASTNode::DecPointer(name),
+                            Some((content, _)) if content.is_empty() => {
+                                ASTNode::DecArrayVariable(name)
+                            }
+                            Some((mut content, index)) => match content.pop() {
+                                Some(Spanned {
+                                    node: FoldedStreamNode::NumericLiteral(size),
+                                    span,
+                                }) if content.is_empty() => {
+                                    let size = NumericLiteral::parse(&size, span)?;
+                                    match size.as_index() {
+                                        Some(x) if x > 0 => ASTNode::DecArraySized(name, size),
+                                        _ => {
+                                            return Err(SblError::UnexpectedToken(
+                                                size.to_string(),
+                                                span,
+                                            ))
+                                        }
+                                    }
+                                }
+                                _ => {
+                                    return Err(SblError::UnexpectedToken(
+                                        "[...]".to_string(),
+                                        index,
+                                    ))
+                                }
+                            },
+                        }
+                    } else {
+                        // The tokenizer keeps `name:type` together as one ident
+                        match name.split_once(':') {
+                            Some((name, ty)) if declare => {
+                                ASTNode::DecTyped(name.to_string(), parse_type_component(ty, end)?)
+                            }
+                            Some(_) => return Err(SblError::UnexpectedToken(name, end)),
+                            None if declare => ASTNode::Dec(name),
+                            None => ASTNode::Assign(name),
+                        }
+                    }
+                }
+                // #x
+                FoldedStreamNode::Octothorp => {
+                    let (name, end) = ident(node, span)?;
+                    span = span.to(end);
+
+                    ASTNode::Address(name)
+                }
+                FoldedStreamNode::AtSign => ASTNode::Ident("@".to_string()),
+                FoldedStreamNode::QMark => {
+                    return Err(SblError::UnexpectedToken("?".to_string(), span))
+                }
+            };
+
+            Ok(Spanned::new(x, span))
         } else {
-            bail!("Token stream empty at EOF")
+            Err(SblError::UnexpectedEof(Span::default()))
         }
     }
 }
 
-pub fn parse_type_component() -> TypeComponent {
-    todo!()
+fn is_type_name(s: &str) -> bool {
+    let mut chars = s.chars();
+
+    matches!(chars.next(), Some(x) if x.is_alphabetic() || x == '_')
+        && chars.all(|x| x.is_alphanumeric() || x == '_')
 }
-pub fn parse_types(content: Vec<FoldedStreamNode>) -> anyhow::Result<Vec<TypingASTNode>> {
-    todo!()
+
+// Walk+Quack:a | Walk+Quack | a | a! | a* | *
+pub fn parse_type_component(s: &str, span: Span) -> Result<TypeComponent, SblError> {
+    let (s, poly) = match s.strip_suffix('*') {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    let (s, explicit) = match s.strip_suffix('!') {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+
+    let (components, variable) = match s.split_once(':') {
+        Some((components, variable)) => (Some(components), Some(variable)),
+        None if s.is_empty() => (None, None),
+        None if s.starts_with(char::is_lowercase) => (None, Some(s)),
+        None => (Some(s), None),
+    };
+
+    let type_name_components = match components {
+        Some(components) => components
+            .split('+')
+            .map(|x| {
+                if is_type_name(x) {
+                    Ok(x.to_string())
+                } else {
+                    Err(SblError::InvalidTypeName(x.to_string(), span))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+
+    let variable = match variable {
+        Some(x) if is_type_name(x) => Some(x.to_string()),
+        Some(x) => return Err(SblError::InvalidTypeVariable(x.to_string(), span)),
+        None => None,
+    };
+
+    if variable.is_none() && type_name_components.is_empty() && !poly {
+        return Err(SblError::EmptyType(span));
+    }
+    if explicit && variable.is_none() {
+        return Err(SblError::ExplicitNonVariable(s.to_string(), span));
+    }
+
+    Ok(TypeComponent {
+        variable,
+        type_name_components,
+        explicit,
+        poly,
+    })
 }
+// -a@1 | -a@(1 | 2), the pattern may be spread over the following tokens
+fn parse_pattern(
+    ident: &str,
+    span: Span,
+    content: &mut impl Iterator<Item = Spanned<FoldedStreamNode>>,
+) -> Result<(String, Vec<NumericLiteral>, Span), SblError> {
+    let (component, mut text) = match ident.split_once('@') {
+        Some((component, text)) => (component.to_string(), text.to_string()),
+        None => return Err(SblError::MalformedPattern(span)),
+    };
+    let mut span = span;
+
+    if text.starts_with('(') {
+        while !text.ends_with(')') {
+            match content.next() {
+                Some(Spanned {
+                    node: FoldedStreamNode::Ident(x) | FoldedStreamNode::NumericLiteral(x),
+                    span: next,
+                }) => {
+                    text.push(' ');
+                    text.push_str(&x);
+                    span = span.to(next);
+                }
+                _ => return Err(SblError::MalformedPattern(span)),
+            }
+        }
+
+        text = text[1..text.len() - 1].to_string();
+    }
+
+    let patterns = text
+        .split('|')
+        .map(|x| match x.trim() {
+            "" => Err(SblError::MalformedPattern(span)),
+            x => NumericLiteral::parse(x, span),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-pub fn build_tree(stream: Vec<Token>) -> anyhow::Result<Vec<TopLevelNode>> {
+    Ok((component, patterns, span))
+}
+
+pub fn parse_types(
+    content: Vec<Spanned<FoldedStreamNode>>,
+) -> Result<Vec<TypingASTNode>, SblError> {
+    let mut out = Vec::with_capacity(content.len());
+    let mut content = content.into_iter();
+
+    while let Some(Spanned { node, span }) = content.next() {
+        let ident = match node {
+            FoldedStreamNode::Ident(x) => x,
+            x => return Err(SblError::UnexpectedToken(describe(&x), span)),
+        };
+
+        out.push(if ident == "!" {
+            TypingASTNode::Never
+        } else if ident.contains('@') {
+            let (component, patterns, span) = parse_pattern(&ident, span, &mut content)?;
+            match component.strip_prefix('-') {
+                Some(x) => TypingASTNode::Match(parse_type_component(x, span)?, patterns),
+                None => return Err(SblError::MalformedPattern(span)),
+            }
+        } else if let Some(x) = ident.strip_prefix('-') {
+            TypingASTNode::Pop(parse_type_component(x, span)?)
+        } else if let Some(x) = ident.strip_prefix('+') {
+            TypingASTNode::Push(parse_type_component(x, span)?)
+        } else {
+            return Err(SblError::UnprefixedType(ident, span));
+        })
+    }
+
+    Ok(out)
+}
+
+pub fn build_expression(stream: Vec<Spanned<Token>>) -> Result<Vec<Spanned<ASTNode>>, SblError> {
     let mut out = Vec::new();
 
-    let stream = fold_stream(stream);
+    let stream = fold_stream(stream)?;
     let mut stream: FoldedStream = stream.into_iter().peekable();
 
-    while let Some(x) = stream.next() {
-        out.push(match x {
-            FoldedStreamNode::QMark => match (stream.next(), stream.next()) {
-                (Some(FoldedStreamNode::Ident(ident)), Some(FoldedStreamNode::Square(content))) => {
-                    TopLevelNode::Typing(ident, parse_types(content)?)
-                }
+    while stream.peek().is_some() {
+        out.push(ASTNode::new(&mut stream)?);
+    }
+
+    Ok(fold_constants(out))
+}
+
+fn is_top_level(node: &Spanned<FoldedStreamNode>) -> bool {
+    match &node.node {
+        FoldedStreamNode::AtSign | FoldedStreamNode::QMark => true,
+        FoldedStreamNode::Ident(x) => x == "use",
+        _ => false,
+    }
+}
 
-                _ => bail!("Typing must be followed by ident and bracket"),
-            },
-            FoldedStreamNode::AtSign => match stream.next() {
-                Some(FoldedStreamNode::Ident(ident)) => {
-                    TopLevelNode::WordDeclare(ident, ASTNode::new(&mut stream)?)
+// Every node is spanned by its header, `@ident`, `?ident` or `use "path"`
+fn top_level_node(
+    stream: &mut FoldedStream,
+    Spanned { node, span }: Spanned<FoldedStreamNode>,
+) -> Result<Spanned<TopLevelNode>, SblError> {
+    let is_ident = |x: &Spanned<FoldedStreamNode>| matches!(x.node, FoldedStreamNode::Ident(_));
+
+    Ok(match node {
+        FoldedStreamNode::QMark => match (
+            stream.next_if(is_ident),
+            stream.next_if(|x| matches!(x.node, FoldedStreamNode::Square(_))),
+        ) {
+            (
+                Some(Spanned {
+                    node: FoldedStreamNode::Ident(ident),
+                    span: name,
+                }),
+                Some(Spanned {
+                    node: FoldedStreamNode::Square(content),
+                    ..
+                }),
+            ) => Spanned::new(
+                TopLevelNode::Typing(ident, parse_types(content)?),
+                span.to(name),
+            ),
+
+            _ => return Err(SblError::MalformedTyping(span)),
+        },
+        FoldedStreamNode::AtSign => match stream.next_if(is_ident) {
+            Some(Spanned {
+                node: FoldedStreamNode::Ident(ident),
+                span: name,
+            }) => {
+                if stream.peek().is_none_or(is_top_level) {
+                    return Err(SblError::MissingWordBody(ident, name));
                 }
 
-                _ => bail!("Word deceleration must be followed by ident"),
-            },
-            _ => panic!("Invalid top-level deceleration"),
-        })
+                let mut body = fold_constants(vec![ASTNode::new(stream)?]);
+                Spanned::new(
+                    TopLevelNode::WordDeclare(ident, body.remove(0)),
+                    span.to(name),
+                )
+            }
+
+            _ => return Err(SblError::MissingWordName(span)),
+        },
+        FoldedStreamNode::Ident(x) if x == "use" => {
+            match stream.next_if(|x| matches!(x.node, FoldedStreamNode::StringLiteral(_))) {
+                Some(Spanned {
+                    node: FoldedStreamNode::StringLiteral(path),
+                    span: path_span,
+                }) => Spanned::new(TopLevelNode::Use(path), span.to(path_span)),
+                _ => return Err(SblError::MalformedImport(span)),
+            }
+        }
+        x => return Err(SblError::InvalidTopLevel(describe(&x), span)),
+    })
+}
+
+pub fn build_tree(stream: Vec<Spanned<Token>>) -> Result<Vec<Spanned<TopLevelNode>>, SblError> {
+    let mut out = Vec::new();
+
+    let stream = fold_stream(stream)?;
+    let mut stream: FoldedStream = stream.into_iter().peekable();
+
+    while let Some(node) = stream.next() {
+        out.push(top_level_node(&mut stream, node)?);
     }
 
     Ok(out)
 }
 
+// Like build_tree but skips to the next `@` or `?` after an error, so every malformed
+// deceleration is reported in one go
+pub fn build_tree_recovering(
+    stream: Vec<Spanned<Token>>,
+) -> (Vec<Spanned<TopLevelNode>>, Vec<SblError>) {
+    let mut out = Vec::new();
+    let mut errors = Vec::new();
+
+    let (stream, _) = fold_until(
+        &mut stream.into_iter().peekable(),
+        &mut Vec::new(),
+        &mut errors,
+    );
+    let mut stream: FoldedStream = stream.into_iter().peekable();
+
+    while let Some(node) = stream.next() {
+        match top_level_node(&mut stream, node) {
+            Ok(node) => out.push(node),
+            Err(err) => {
+                errors.push(err);
+                while stream.next_if(|x| !is_top_level(x)).is_some() {}
+            }
+        }
+    }
+
+    // Bracket errors are found while folding, before the others
+    errors.sort_by_key(|x| x.span().start);
+    (out, errors)
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::error::SblError;
     use crate::tokenizer::tokenizer;
 
-    use super::{build_tree, fold_stream};
+    use super::{build_tree, build_tree_recovering, fold_stream};
 
     #[test]
     fn exp_fold() {
         let program = "?main [] @main {\"Hello world!\\n\".}";
         let program = tokenizer(program.to_string()).unwrap();
-        println!("{:?}", fold_stream(program));
+        println!("{:?}", fold_stream(program).unwrap());
     }
 
     #[test]
@@ -262,6 +643,145 @@ mod tests {
         let program = build_tree(program).unwrap();
         println!("{:?}", program);
     }
+
+    #[test]
+    fn it_locates_mismatched_brackets() {
+        let program = tokenizer("@main {\n  [ 1 }".to_string()).unwrap();
+        let err = build_tree(program).unwrap_err();
+
+        assert!(matches!(
+            err,
+            SblError::MismatchedBracket {
+                found: '}',
+                open: '[',
+                ..
+            }
+        ));
+        assert_eq!((err.span().line, err.span().column), (2, 7));
+        assert_eq!(
+            err.to_string(),
+            "Mismatched }, the [ opened at 2:3 is still open"
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_top_level() {
+        let program = tokenizer("@main { } 1 2".to_string()).unwrap();
+
+        assert!(matches!(
+            build_tree(program),
+            Err(SblError::InvalidTopLevel(x, _)) if x == "1"
+        ));
+    }
+
+    #[test]
+    fn it_recovers_at_the_next_deceleration() {
+        let program = "@one 1\n@ { 2 }\n?two [Int]\n@two { 2 ] }\n@three { 3 }\n@four";
+        let program = tokenizer(program.to_string()).unwrap();
+        let (tree, errors) = build_tree_recovering(program);
+
+        let names: Vec<_> = tree
+            .iter()
+            .map(|x| match &x.node {
+                super::TopLevelNode::WordDeclare(name, _) => name.clone(),
+                super::TopLevelNode::Typing(name, _) => format!("?{}", name),
+                super::TopLevelNode::Use(path) => format!("use {}", path),
+            })
+            .collect();
+        assert_eq!(names, vec!["one", "two", "three"]);
+
+        let lines: Vec<_> = errors.iter().map(|x| x.span().line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 6]);
+    }
+
+    #[test]
+    fn it_parses_variables() {
+        use super::ASTNode::*;
+
+        let program = tokenizer("1 ::x 2 ::y:Int x :y".to_string()).unwrap();
+        let nodes = super::build_expression(program).unwrap();
+
+        assert_eq!(nodes.len(), 6);
+        assert!(matches!(&nodes[1].node, Dec(x) if x == "x"));
+        assert!(matches!(
+            &nodes[3].node,
+            DecTyped(x, ty) if x == "y" && ty.type_name_components == vec!["Int".to_string()]
+        ));
+        assert!(matches!(&nodes[4].node, Ident(x) if x == "x"));
+        assert!(matches!(&nodes[5].node, Assign(x) if x == "y"));
+        assert_eq!((nodes[3].span.start, nodes[3].span.end), (8, 15));
+    }
+
+    #[test]
+    fn it_parses_patterns() {
+        use super::TypingASTNode::*;
+        use crate::numeric_litteral::NumericLiteral::*;
+
+        let program = "?a [-a@(1 | 2) -Int@3 +a] ?b [-a@()] ?c [+a@1]";
+        let program = tokenizer(program.to_string()).unwrap();
+        let program = build_tree_recovering(program);
+
+        let types = match &program.0[0].node {
+            super::TopLevelNode::Typing(_, types) => types,
+            x => panic!("Expected typing, got {:?}", x),
+        };
+
+        assert!(matches!(&types[0], Match(_, x) if x == &vec![SysInt(1), SysInt(2)]));
+        assert!(matches!(&types[1], Match(_, x) if x == &vec![SysInt(3)]));
+        assert!(matches!(&types[2], Push(_)));
+        assert_eq!(program.1.len(), 2);
+    }
+
+    #[test]
+    fn it_parses_types() {
+        use super::{TypeComponent, TypingASTNode::*};
+
+        let program = "?a [-Walk+Quack:a -b! -* +a* +Int !]";
+        let program = tokenizer(program.to_string()).unwrap();
+        let program = build_tree(program).unwrap();
+
+        let types = match &program[0].node {
+            super::TopLevelNode::Typing(name, types) if name == "a" => types,
+            x => panic!("Expected typing, got {:?}", x),
+        };
+
+        assert_eq!(
+            types,
+            &vec![
+                Pop(TypeComponent {
+                    variable: Some("a".to_string()),
+                    type_name_components: vec!["Walk".to_string(), "Quack".to_string()],
+                    explicit: false,
+                    poly: false,
+                }),
+                Pop(TypeComponent {
+                    variable: Some("b".to_string()),
+                    type_name_components: vec![],
+                    explicit: true,
+                    poly: false,
+                }),
+                Pop(TypeComponent {
+                    variable: None,
+                    type_name_components: vec![],
+                    explicit: false,
+                    poly: true,
+                }),
+                Push(TypeComponent {
+                    variable: Some("a".to_string()),
+                    type_name_components: vec![],
+                    explicit: false,
+                    poly: true,
+                }),
+                Push(TypeComponent {
+                    variable: None,
+                    type_name_components: vec!["Int".to_string()],
+                    explicit: false,
+                    poly: false,
+                }),
+                Never,
+            ]
+        );
+    }
 }
 
 // TODO: Compile time code compilation without proc macros
@@ -285,7 +805,7 @@ macro_rules! sbl_exprs {
 // !            Never
 // -*           Read an unspecific amount of untyped stack entries or the entire stack
 // +a*          Write an unspecific amount of values of type a to the stack
-// -a@(pattern) Pattern match
+// -a@(pattern) Pattern match, a is the type of the literals in every clause of the word
 
 // Pre-declared words
 // dup      Duplicate   -a! +a! +a!
@@ -304,6 +824,40 @@ macro_rules! sbl_exprs {
 // /=       Neq         -Eq:a -a +Bool
 // >        Lt          -Ord:a -a +Bool
 // <        Gt          -Ord:a -a +Bool
+// strlen   Length      -Str +Int
+// strcat   Concatenate -Str -Str +Str
+// strslice Slice       -Str -Int -Int +Str
+// nextchar Decode      -Str -Int +U32 +Int
+// charstr  Encode      -U32 +Str
+// times    Repeat      -* -Int -Callable +a*
+// while    Loop        -* -Callable -Callable +a*
+// for      Count       -* -Int -Int -Callable +a*
+// size     List length -List +Int
+// at       Index       -List -Int +a
+
+// Prelude words, see prelude.sbl, a program can replace them and they are always reachable
+// as prelude/<word>
+// over rot nip tuck 2dup abs min max mod each map filter foreach
+// len concat substr eachchar charcount
+
+// . writes a float like printf's %g with the fewest digits that read back as the same value
+
+// Strings are UTF-8 bytes with a length, len, substr, strslice and nextchar count in bytes
+// substr takes a start and a count, strslice a start and an end
+// A char literal is the U32 code point of its char, 'λ' is 955u32
+
+// Escapes in string and char literals
+// \n \r \t \0 \\ \" \'  The usual control and quote characters
+// \x41                  An ASCII character, up to 7f
+// \u{1F600}             A unicode scalar value, one to six hex digits
+// \ at a line end       Skips the newline, \r\n included, and the next line's indentation
+
+// Other string literals
+// r"\d+"                No escapes
+// r#"{"a": 1}"#         Any number of # around the quotes, the string ends at a matching "#
+// """                   Multi-line, escapes are processed and the indentation every line
+//     text              has in common is removed, blank lines right after the opening
+//     """               and before the closing quotes are dropped
 
 /*
 ; HELLO WORLD
@@ -321,14 +875,33 @@ macro_rules! sbl_exprs {
 @main { 10i fib . }
 
 ; VECTOR ADD
-?for [-a -a -a]
-@for {}
+?add_at [-Ptr -Ptr -Ptr -Int]
+@add_at { ::i :#sum :#b :#a i $a[] i $b[] + i $:sum[] }
 
 @main {
-    10 ::size
-    $:size :#array[]
+    2 ::size
+    size :#a[] size :#b[] :#sum[2]
+
+    1 0 $:a[] 2 1 $:a[]
+    3 0 $:b[] 4 1 $:b[]
+
+    a b sum 0 add_at
+    a b sum 1 add_at
+    0 $sum[] . 1 $sum[] .
 }
 
+; LOOPS
+?countdown [-Int@0]
+@countdown { drop }
+
+?countdown [-Int]
+@countdown { dup . 1 - countdown }
+
+@main { 0 10 { . } for 3 { "hi" . } times 1 { dup 100 < } { 2 * } while . 10 countdown }
+
+; LISTS
+@main { [1 2 3 0xff] ::l l size . l 3 at . l { . } foreach }
+
 ; IF
 
 @main { 1 0 = { "They are equal" . } if { "They are not equal" } else }
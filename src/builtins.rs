@@ -0,0 +1,512 @@
+use anyhow::bail;
+use num_bigint::BigInt;
+use num_traits::Zero;
+
+use crate::{
+    ast::{parse_types, FoldedStreamNode, TypingASTNode},
+    interpreter::{Interpreter, Value},
+    namemap::{NameMap, NameMapNode},
+    numeric_litteral::{int_bounds, NumericLiteral},
+    span::{Span, Spanned},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Dup,
+    Drop,
+    Swap,
+    Pick,
+    Call,
+    Put,
+    If,
+    Else,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Neq,
+    Gt,
+    Lt,
+    StrLen,
+    StrCat,
+    StrSlice,
+    NextChar,
+    CharStr,
+    Times,
+    While,
+    For,
+    Count,
+    At,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 26] = [
+        Builtin::Dup,
+        Builtin::Drop,
+        Builtin::Swap,
+        Builtin::Pick,
+        Builtin::Call,
+        Builtin::Put,
+        Builtin::If,
+        Builtin::Else,
+        Builtin::Add,
+        Builtin::Sub,
+        Builtin::Mul,
+        Builtin::Div,
+        Builtin::Eq,
+        Builtin::Neq,
+        Builtin::Gt,
+        Builtin::Lt,
+        Builtin::StrLen,
+        Builtin::StrCat,
+        Builtin::StrSlice,
+        Builtin::NextChar,
+        Builtin::CharStr,
+        Builtin::Times,
+        Builtin::While,
+        Builtin::For,
+        Builtin::Count,
+        Builtin::At,
+    ];
+
+    pub fn name(self) -> &'static str {
+        use Builtin::*;
+        match self {
+            Dup => "dup",
+            Drop => "drop",
+            Swap => "swap",
+            Pick => "pick",
+            Call => "@",
+            Put => ".",
+            If => "if",
+            Else => "else",
+            Add => "+",
+            Sub => "-",
+            Mul => "*",
+            Div => "/",
+            Eq => "=",
+            Neq => "/=",
+            Gt => ">",
+            Lt => "<",
+            StrLen => "strlen",
+            StrCat => "strcat",
+            StrSlice => "strslice",
+            NextChar => "nextchar",
+            CharStr => "charstr",
+            Times => "times",
+            While => "while",
+            For => "for",
+            Count => "size",
+            At => "at",
+        }
+    }
+
+    fn signature_source(self) -> &'static str {
+        use Builtin::*;
+        match self {
+            Dup => "-a! +a! +a!",
+            Drop => "-a",
+            Swap => "-a! -b! +b! +a!",
+            Pick => "-a +b",
+            Call => "-Callable",
+            Put => "-Writeable",
+            If | Else => "-a! -Callable +a!",
+            Add => "-Add:a -a +a",
+            Sub => "-Sub:a -a +a",
+            Mul => "-Mul:a -a +a",
+            Div => "-Div:a -a +a",
+            Eq | Neq => "-Eq:a -a +Bool",
+            Gt | Lt => "-Ord:a -a +Bool",
+            StrLen => "-Str +Int",
+            StrCat => "-Str -Str +Str",
+            StrSlice => "-Str -Int -Int +Str",
+            NextChar => "-Str -Int +U32 +Int",
+            CharStr => "-U32 +Str",
+            Times => "-* -Int -Callable +a*",
+            While => "-* -Callable -Callable +a*",
+            For => "-* -Int -Int -Callable +a*",
+            Count => "-List +Int",
+            At => "-List -Int +a",
+        }
+    }
+
+    pub fn signature(self) -> Vec<TypingASTNode> {
+        let content = self
+            .signature_source()
+            .split_whitespace()
+            .map(|x| Spanned::new(FoldedStreamNode::Ident(x.to_string()), Span::default()))
+            .collect();
+
+        parse_types(content).expect("invalid builtin signature")
+    }
+
+    pub fn interpret(self, interpreter: &mut Interpreter) -> anyhow::Result<()> {
+        use Builtin::*;
+        match self {
+            Dup => {
+                let a = interpreter.pop()?;
+                interpreter.push(a.clone());
+                interpreter.push(a);
+            }
+            Drop => {
+                interpreter.pop()?;
+            }
+            Swap => {
+                let b = interpreter.pop()?;
+                let a = interpreter.pop()?;
+                interpreter.push(b);
+                interpreter.push(a);
+            }
+            Pick => {
+                let index = match interpreter.pop()? {
+                    Value::Numeric(n) => match n.as_index() {
+                        Some(x) => x,
+                        None => bail!("Can not pick with index {}", n.to_string()),
+                    },
+                    x => bail!("Can not pick with index {}", x),
+                };
+
+                match interpreter.stack.len().checked_sub(index + 1) {
+                    Some(x) => interpreter.push(interpreter.stack[x].clone()),
+                    None => bail!("Stack underflow"),
+                }
+            }
+            Call => {
+                let body = interpreter.pop_quote()?;
+                interpreter.call_closure(&body)?;
+            }
+            Put => {
+                let value = interpreter.pop()?;
+                interpreter.write(&value)?;
+            }
+            If | Else => {
+                let body = interpreter.pop_quote()?;
+                let condition = interpreter.pop()?;
+
+                if condition.truthy()? == (self == If) {
+                    interpreter.call_closure(&body)?;
+                }
+                interpreter.push(condition);
+            }
+            Eq | Neq => {
+                let b = interpreter.pop()?;
+                let a = interpreter.pop()?;
+
+                let equal = match (&a, &b) {
+                    (Value::Str(a), Value::Str(b)) => a == b,
+                    (Value::Numeric(_), Value::Numeric(_)) => {
+                        return binary(self, a, b).map(|x| interpreter.push(x))
+                    }
+                    _ => bail!("Can not compare {} and {}", a, b),
+                };
+
+                interpreter.push(Value::Numeric(NumericLiteral::Boolean(
+                    equal == (self == Eq),
+                )));
+            }
+            Add | Sub | Mul | Div | Gt | Lt => {
+                let b = interpreter.pop()?;
+                let a = interpreter.pop()?;
+
+                interpreter.push(binary(self, a, b)?);
+            }
+            // The primitives under len, concat and substr, offsets are in bytes
+            StrLen => {
+                let s = interpreter.pop_str()?;
+                interpreter.push(Value::Numeric(NumericLiteral::SysInt(s.len() as i64)));
+            }
+            StrCat => {
+                let b = interpreter.pop_str()?;
+                let a = interpreter.pop_str()?;
+                interpreter.push(Value::Str(a + &b));
+            }
+            StrSlice => {
+                let end = interpreter.pop_index()?;
+                let start = interpreter.pop_index()?;
+                let s = interpreter.pop_str()?;
+
+                match s.get(start..end) {
+                    Some(x) => interpreter.push(Value::Str(x.to_string())),
+                    None => bail!("Slice {}..{} is out of bounds of {:?}", start, end, s),
+                }
+            }
+            // The code point starting at a byte offset and the offset of the one after it
+            NextChar => {
+                let offset = interpreter.pop_index()?;
+                let s = interpreter.pop_str()?;
+
+                match s.get(offset..).and_then(|x| x.chars().next()) {
+                    Some(x) => {
+                        interpreter.push(Value::Numeric(NumericLiteral::Uint(
+                            32,
+                            BigInt::from(x as u32),
+                        )));
+                        interpreter.push(Value::Numeric(NumericLiteral::SysInt(
+                            (offset + x.len_utf8()) as i64,
+                        )));
+                    }
+                    None => bail!("No character starts at {} in {:?}", offset, s),
+                }
+            }
+            CharStr => {
+                let code = interpreter.pop_index()?;
+
+                match std::char::from_u32(code as u32).filter(|_| code <= u32::MAX as usize) {
+                    Some(x) => interpreter.push(Value::Str(x.to_string())),
+                    None => bail!("{:#x} is not a unicode scalar value", code),
+                }
+            }
+            Times => {
+                let body = interpreter.pop_quote()?;
+                let count = interpreter.pop_int()?;
+
+                for _ in 0..count {
+                    interpreter.call_closure(&body)?;
+                }
+            }
+            While => {
+                let body = interpreter.pop_quote()?;
+                let condition = interpreter.pop_quote()?;
+
+                loop {
+                    interpreter.call_closure(&condition)?;
+                    if !interpreter.pop()?.truthy()? {
+                        break;
+                    }
+                    interpreter.call_closure(&body)?;
+                }
+            }
+            // The body is run with every index from start up to but not including end
+            For => {
+                let body = interpreter.pop_quote()?;
+                let end = interpreter.pop_int()?;
+                let start = interpreter.pop_int()?;
+
+                for i in start..end {
+                    interpreter.push(Value::Numeric(NumericLiteral::SysInt(i)));
+                    interpreter.call_closure(&body)?;
+                }
+            }
+            Count => {
+                let list = interpreter.pop_list()?;
+                interpreter.push(Value::Numeric(NumericLiteral::SysInt(list.len() as i64)));
+            }
+            At => {
+                let index = interpreter.pop_index()?;
+                let list = interpreter.pop_list()?;
+
+                match list.get(index) {
+                    Some(x) => interpreter.push(x.clone()),
+                    None => bail!(
+                        "Index {} is out of bounds of a list of {}",
+                        index,
+                        list.len()
+                    ),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn binary(builtin: Builtin, a: Value, b: Value) -> anyhow::Result<Value> {
+    match (a, b) {
+        (Value::Numeric(a), Value::Numeric(b)) => {
+            Ok(Value::Numeric(numeric_binary(builtin, &a, &b)?))
+        }
+        (a, b) => bail!("Can not apply {} to {} and {}", builtin.name(), a, b),
+    }
+}
+
+// Reduces modulo 2^width into the unsigned range
+pub fn wrap_uint(width: u16, n: BigInt) -> BigInt {
+    let (_, max) = int_bounds(width, false);
+    ((n % &max) + &max) % max
+}
+
+// Sign extends the low `width` bits
+pub fn wrap_int(width: u16, n: BigInt) -> BigInt {
+    let (min, _) = int_bounds(width, true);
+    wrap_uint(width, n - &min) + min
+}
+
+// Both operands must be of the same type and width, the result wraps around at that width
+pub fn numeric_binary(
+    builtin: Builtin,
+    a: &NumericLiteral,
+    b: &NumericLiteral,
+) -> anyhow::Result<NumericLiteral> {
+    use NumericLiteral::*;
+
+    fn compare<T: PartialOrd>(builtin: Builtin, a: T, b: T) -> Option<NumericLiteral> {
+        Some(Boolean(match builtin {
+            Builtin::Eq => a == b,
+            Builtin::Neq => a != b,
+            Builtin::Gt => a > b,
+            Builtin::Lt => a < b,
+            _ => return None,
+        }))
+    }
+
+    let zero = match b {
+        Int(_, n) | Uint(_, n) => n.is_zero(),
+        SysInt(n) => *n == 0,
+        SysUint(n) => *n == 0,
+        _ => false,
+    };
+    if builtin == Builtin::Div && zero {
+        bail!("Division by zero")
+    }
+
+    let result = match (a, b) {
+        (Int(wa, a), Int(wb, b)) if wa == wb => compare(builtin, a, b)
+            .or_else(|| Some(Int(*wa, wrap_int(*wa, big_arithmetic(builtin, a, b)?)))),
+        (SysInt(a), SysInt(b)) => {
+            compare(builtin, a, b).or_else(|| Some(SysInt(int_arithmetic(builtin, *a, *b)?)))
+        }
+        (Uint(wa, a), Uint(wb, b)) if wa == wb => compare(builtin, a, b)
+            .or_else(|| Some(Uint(*wa, wrap_uint(*wa, big_arithmetic(builtin, a, b)?)))),
+        (SysUint(a), SysUint(b)) => {
+            compare(builtin, a, b).or_else(|| Some(SysUint(uint_arithmetic(builtin, *a, *b)?)))
+        }
+        (Float(32, a), Float(32, b)) => {
+            let (a, b) = (*a as f32, *b as f32);
+            compare(builtin, a, b)
+                .or_else(|| Some(Float(32, float_arithmetic(builtin, a, b)? as f64)))
+        }
+        (Float(wa, a), Float(wb, b)) if wa == wb => {
+            compare(builtin, a, b).or_else(|| Some(Float(*wa, float_arithmetic(builtin, *a, *b)?)))
+        }
+        (Boolean(a), Boolean(b)) => match builtin {
+            Builtin::Eq | Builtin::Neq => compare(builtin, a, b),
+            _ => None,
+        },
+        _ => bail!(
+            "Can not apply {} to {} and {}",
+            builtin.name(),
+            a.type_name(),
+            b.type_name()
+        ),
+    };
+
+    match result {
+        Some(x) => Ok(x),
+        None => bail!(
+            "Can not apply {} to {} and {}",
+            builtin.name(),
+            a.to_string(),
+            b.to_string()
+        ),
+    }
+}
+
+fn int_arithmetic(builtin: Builtin, a: i64, b: i64) -> Option<i64> {
+    match builtin {
+        Builtin::Add => Some(a.wrapping_add(b)),
+        Builtin::Sub => Some(a.wrapping_sub(b)),
+        Builtin::Mul => Some(a.wrapping_mul(b)),
+        Builtin::Div if b != 0 => Some(a.wrapping_div(b)),
+        _ => None,
+    }
+}
+
+fn uint_arithmetic(builtin: Builtin, a: u64, b: u64) -> Option<u64> {
+    match builtin {
+        Builtin::Add => Some(a.wrapping_add(b)),
+        Builtin::Sub => Some(a.wrapping_sub(b)),
+        Builtin::Mul => Some(a.wrapping_mul(b)),
+        Builtin::Div if b != 0 => Some(a / b),
+        _ => None,
+    }
+}
+
+// Exact, the caller wraps the result around at its width
+fn big_arithmetic(builtin: Builtin, a: &BigInt, b: &BigInt) -> Option<BigInt> {
+    match builtin {
+        Builtin::Add => Some(a + b),
+        Builtin::Sub => Some(a - b),
+        Builtin::Mul => Some(a * b),
+        Builtin::Div if !b.is_zero() => Some(a / b),
+        _ => None,
+    }
+}
+
+fn float_arithmetic<
+    T: std::ops::Add<Output = T>
+        + std::ops::Sub<Output = T>
+        + std::ops::Mul<Output = T>
+        + std::ops::Div<Output = T>,
+>(
+    builtin: Builtin,
+    a: T,
+    b: T,
+) -> Option<T> {
+    match builtin {
+        Builtin::Add => Some(a + b),
+        Builtin::Sub => Some(a - b),
+        Builtin::Mul => Some(a * b),
+        Builtin::Div => Some(a / b),
+        _ => None,
+    }
+}
+
+pub fn register(map: &mut NameMap) {
+    for builtin in Builtin::ALL.iter() {
+        map.insert(
+            builtin.name().to_string(),
+            NameMapNode::Builtin {
+                builtin: *builtin,
+                signature: builtin.signature(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigInt;
+
+    use super::{numeric_binary, Builtin};
+    use crate::numeric_litteral::NumericLiteral::*;
+
+    fn big(n: i128) -> BigInt {
+        BigInt::from(n)
+    }
+
+    #[test]
+    fn it_wraps_at_width() {
+        assert_eq!(
+            numeric_binary(Builtin::Add, &Uint(8, big(200)), &Uint(8, big(100))).unwrap(),
+            Uint(8, big(44))
+        );
+        assert_eq!(
+            numeric_binary(Builtin::Add, &Int(8, big(100)), &Int(8, big(100))).unwrap(),
+            Int(8, big(-56))
+        );
+        assert_eq!(
+            numeric_binary(Builtin::Lt, &Int(32, big(1)), &Int(32, big(2))).unwrap(),
+            Boolean(true)
+        );
+        assert_eq!(
+            numeric_binary(Builtin::Sub, &Uint(128, big(0)), &Uint(128, big(1))).unwrap(),
+            Uint(128, BigInt::from(u128::MAX))
+        );
+        assert_eq!(
+            numeric_binary(
+                Builtin::Mul,
+                &Int(256, big(1) << 195),
+                &Int(256, big(1) << 60)
+            )
+            .unwrap(),
+            Int(256, BigInt::from(-1) << 255)
+        );
+    }
+
+    #[test]
+    fn it_rejects_mixed_widths() {
+        assert!(numeric_binary(Builtin::Add, &Int(8, big(1)), &Int(16, big(1))).is_err());
+        assert!(numeric_binary(Builtin::Div, &SysInt(1), &SysInt(0)).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ast::ASTNode,
+    namemap::{NameMap, NameMapNode},
+    span::Spanned,
+};
+
+// Edges go from a word to the words it calls, names missing from the map are left out
+pub struct CallGraph<'a> {
+    names: Vec<&'a str>,
+    calls: HashMap<&'a str, Vec<&'a str>>,
+}
+
+impl<'a> CallGraph<'a> {
+    pub fn new(map: &'a NameMap) -> Self {
+        let mut names: Vec<&str> = map.keys().map(|x| x.as_str()).collect();
+        names.sort_unstable();
+
+        let mut calls = HashMap::with_capacity(names.len());
+        for name in names.iter() {
+            let mut callees: Vec<&str> = match &map[*name] {
+                NameMapNode::Word { depends_on, .. }
+                | NameMapNode::Overloaded { depends_on, .. } => {
+                    depends_on.iter().map(|x| x.node.as_str()).collect()
+                }
+                NameMapNode::AliasedWord(target, _) => vec![target.as_str()],
+                _ => Vec::new(),
+            };
+            callees.retain(|x| map.contains_key(*x));
+            callees.sort_unstable();
+            callees.dedup();
+
+            calls.insert(*name, callees);
+        }
+
+        Self { names, calls }
+    }
+
+    pub fn calls(&self, name: &str) -> &[&'a str] {
+        self.calls.get(name).map_or(&[], |x| x.as_slice())
+    }
+
+    pub fn reachable(&self, root: &str) -> HashSet<&'a str> {
+        let mut seen = HashSet::new();
+        let mut todo: Vec<&str> = self.names.iter().copied().filter(|x| *x == root).collect();
+
+        while let Some(name) = todo.pop() {
+            if seen.insert(name) {
+                todo.extend(self.calls(name));
+            }
+        }
+
+        seen
+    }
+
+    // Tarjan's algorithm, every group of mutually recursive words comes after the words it calls
+    pub fn components(&self) -> Vec<Vec<&'a str>> {
+        struct State<'a> {
+            next: usize,
+            index: HashMap<&'a str, usize>,
+            low: HashMap<&'a str, usize>,
+            stack: Vec<&'a str>,
+            on_stack: HashSet<&'a str>,
+            out: Vec<Vec<&'a str>>,
+        }
+
+        fn visit<'a>(graph: &CallGraph<'a>, state: &mut State<'a>, name: &'a str) {
+            state.index.insert(name, state.next);
+            state.low.insert(name, state.next);
+            state.next += 1;
+            state.stack.push(name);
+            state.on_stack.insert(name);
+
+            for callee in graph.calls(name) {
+                if !state.index.contains_key(callee) {
+                    visit(graph, state, callee);
+                    let low = state.low[callee].min(state.low[name]);
+                    state.low.insert(name, low);
+                } else if state.on_stack.contains(callee) {
+                    let low = state.index[callee].min(state.low[name]);
+                    state.low.insert(name, low);
+                }
+            }
+
+            if state.low[name] == state.index[name] {
+                let mut component = Vec::new();
+                while let Some(x) = state.stack.pop() {
+                    state.on_stack.remove(x);
+                    component.push(x);
+                    if x == name {
+                        break;
+                    }
+                }
+                component.sort_unstable();
+                state.out.push(component);
+            }
+        }
+
+        let mut state = State {
+            next: 0,
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: Vec::new(),
+            on_stack: HashSet::new(),
+            out: Vec::new(),
+        };
+        for name in self.names.iter() {
+            if !state.index.contains_key(name) {
+                visit(self, &mut state, name);
+            }
+        }
+
+        state.out
+    }
+
+    // Callees before their callers, mutually recursive words in name order
+    pub fn topological_order(&self) -> Vec<&'a str> {
+        self.components().into_iter().flatten().collect()
+    }
+}
+
+// Drops every word `root` can't reach and returns their names
+pub fn eliminate_dead_words(map: &mut NameMap, root: &str) -> Vec<String> {
+    let reachable: HashSet<String> = CallGraph::new(map)
+        .reachable(root)
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect();
+
+    let mut dead: Vec<String> = map
+        .keys()
+        .filter(|x| !reachable.contains(*x))
+        .cloned()
+        .collect();
+    dead.sort();
+
+    for name in dead.iter() {
+        map.remove(name);
+    }
+
+    dead
+}
+
+// The body before its last node, if that node calls `name` itself and can loop back instead
+pub fn strip_self_tail_call<'b>(
+    body: &'b [Spanned<ASTNode>],
+    name: &str,
+) -> Option<&'b [Spanned<ASTNode>]> {
+    let (last, init) = body.split_last()?;
+
+    let shadowed = init.iter().any(|x| match &x.node {
+        ASTNode::Dec(s)
+        | ASTNode::DecTyped(s, _)
+        | ASTNode::DecPointer(s)
+        | ASTNode::DecArraySized(s, _)
+        | ASTNode::DecArrayVariable(s) => s == name,
+        _ => false,
+    });
+
+    match &last.node {
+        ASTNode::Ident(x) if x == name && !shadowed => Some(init),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eliminate_dead_words, strip_self_tail_call, CallGraph};
+
+    fn name_map(program: &str) -> crate::namemap::NameMap {
+        let program = crate::tokenizer::tokenizer(program.to_string()).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        crate::namemap::extract_name_map(program).unwrap()
+    }
+
+    #[test]
+    fn it_orders_callees_first() {
+        let map =
+            name_map("@main { even } @even { odd } @odd { even leaf } @leaf { dup } @alias leaf");
+        let graph = CallGraph::new(&map);
+
+        let order = graph.topological_order();
+        let position = |name| order.iter().position(|x| *x == name).unwrap();
+        assert!(position("dup") < position("leaf"));
+        assert!(position("leaf") < position("odd"));
+        assert!(position("odd") < position("main"));
+        assert!(position("leaf") < position("alias"));
+
+        let components = graph.components();
+        let groups: Vec<_> = components.iter().filter(|x| x.len() > 1).collect();
+        assert_eq!(groups, vec![&vec!["even", "odd"]]);
+    }
+
+    #[test]
+    fn it_finds_self_tail_calls() {
+        let map = name_map(
+            "@loop { 1 - loop } @shadow { ::shadow shadow } @inner { { inner } @ } @other { loop }",
+        );
+        let body = |name| match &map[name] {
+            crate::namemap::NameMapNode::Word { implementation, .. } => implementation.as_slice(),
+            _ => panic!("{} is not a word", name),
+        };
+
+        assert_eq!(strip_self_tail_call(body("loop"), "loop").unwrap().len(), 2);
+        assert!(strip_self_tail_call(body("shadow"), "shadow").is_none());
+        assert!(strip_self_tail_call(body("inner"), "inner").is_none());
+        assert!(strip_self_tail_call(body("other"), "other").is_none());
+    }
+
+    #[test]
+    fn it_eliminates_dead_words() {
+        let mut map = name_map("@main { used 1 2 + } @used { drop } @unused { used swap + }");
+
+        let dead = eliminate_dead_words(&mut map, "main");
+
+        assert!(dead.contains(&"unused".to_string()));
+        // 1 2 + is folded to 3, main no longer calls +
+        assert!(dead.contains(&"swap".to_string()) && dead.contains(&"+".to_string()));
+        assert!(map.contains_key("used") && map.contains_key("drop"));
+        assert!(!map.contains_key("unused"));
+    }
+}
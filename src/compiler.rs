@@ -1,18 +1,143 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
+use std::convert::TryFrom;
+
+use anyhow::bail;
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Module;
+use inkwell::module::{Linkage, Module};
 use inkwell::passes::PassManager;
-use inkwell::types::BasicMetadataTypeEnum;
+use inkwell::types::{BasicType, FunctionType, IntType, StructType};
 use inkwell::values::{
-    BasicMetadataValueEnum, BasicValue, FloatValue, FunctionValue, IntValue, PointerValue,
+    BasicMetadataValueEnum, CallableValue, FloatValue, FunctionValue, IntValue, PointerValue,
 };
-use inkwell::{FloatPredicate, OptimizationLevel};
+use inkwell::{AddressSpace, FloatPredicate, IntPredicate};
+use num_bigint::{BigInt, Sign};
+use num_traits::ToPrimitive;
+
+use crate::ast::ASTNode;
+use crate::builtins::{wrap_int, wrap_uint, Builtin};
+use crate::callgraph::{strip_self_tail_call, CallGraph};
+use crate::diagnostic::Diagnostic;
+use crate::namemap::{Clause, NameMap, NameMapNode};
+use crate::numeric_litteral::{int_bounds, NumericLiteral};
+use crate::span::{Span, Spanned};
+
+const STACK_SIZE: u64 = 1 << 16;
+// Enough for any number printf writes with at most 17 significant digits
+const FORMAT_SIZE: u64 = 32;
+
+// Every stack cell is { i32 tag, i64 payload }, the tag is kind << 16 | width
+// A width of 0 is the system width
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub tag: u32,
+    pub payload: u64,
+}
+
+pub mod tag {
+    pub const INT: u64 = 1;
+    pub const UINT: u64 = 2;
+    pub const FLOAT: u64 = 3;
+    pub const BOOL: u64 = 4;
+    pub const STR: u64 = 5;
+    pub const QUOTE: u64 = 6;
+    pub const PTR: u64 = 7;
+    pub const LIST: u64 = 8;
+
+    pub const fn of(kind: u64, width: u64) -> u64 {
+        kind << 16 | width
+    }
+
+    // Lowered for every program, the literals of a program can add any other width
+    pub const INT_WIDTHS: [u64; 5] = [0, 8, 16, 32, 64];
+    // Integers wider than 64 bits are boxed, their payload is the address of the value
+    pub const WIDE_WIDTHS: [u64; 2] = [128, 256];
+    pub const FLOAT_WIDTHS: [u64; 2] = [32, 64];
+}
 
-use crate::namemap::NameMap;
+// None for integers wider than 64 bits, which do not fit in a payload
+pub fn literal_cell(n: &NumericLiteral) -> Option<(u64, u64)> {
+    use NumericLiteral::*;
+    Some(match n {
+        Float(32, n) => (tag::of(tag::FLOAT, 32), (*n as f32).to_bits() as u64),
+        Float(size, n) => (tag::of(tag::FLOAT, *size as u64), n.to_bits()),
+        SysUint(n) => (tag::of(tag::UINT, 0), *n),
+        Uint(size, n) if *size <= 64 => (tag::of(tag::UINT, *size as u64), n.to_u64()?),
+        SysInt(n) => (tag::of(tag::INT, 0), *n as u64),
+        Int(size, n) if *size <= 64 => (tag::of(tag::INT, *size as u64), n.to_i64()? as u64),
+        Boolean(b) => (tag::of(tag::BOOL, 1), *b as u64),
+        _ => return None,
+    })
+}
 
-struct Compiler<'ctx, 'a> {
+pub fn cell_literal(cell: &Cell) -> Option<NumericLiteral> {
+    use NumericLiteral::*;
+
+    let width = cell.tag & 0xffff;
+    let signed = match width {
+        0 | 64 => cell.payload as i64,
+        width if width < 64 => ((cell.payload << (64 - width)) as i64) >> (64 - width),
+        _ => 0,
+    };
+    // The value of a wide integer outlives its cell, like the strings of the repl do, the bits
+    // of its last byte past the width are undefined
+    let wide = || unsafe {
+        std::slice::from_raw_parts(cell.payload as *const u8, (width as usize + 7) / 8)
+    };
+
+    Some(match (cell.tag as u64 >> 16, width) {
+        (tag::FLOAT, 32) => Float(32, f32::from_bits(cell.payload as u32) as f64),
+        (tag::FLOAT, width) => Float(width as u8, f64::from_bits(cell.payload)),
+        (tag::UINT, 0) => SysUint(cell.payload),
+        (tag::UINT, width) if width > 64 => Uint(
+            width as u16,
+            wrap_uint(width as u16, BigInt::from_bytes_le(Sign::Plus, wide())),
+        ),
+        (tag::UINT, width) => Uint(width as u16, BigInt::from(cell.payload)),
+        (tag::INT, 0) => SysInt(signed),
+        (tag::INT, width) if width > 64 => Int(
+            width as u16,
+            wrap_int(width as u16, BigInt::from_bytes_le(Sign::Plus, wide())),
+        ),
+        (tag::INT, width) => Int(width as u16, BigInt::from(signed)),
+        (tag::BOOL, _) => Boolean(cell.payload != 0),
+        _ => return None,
+    })
+}
+
+// Numbers, strings and lists are shown like the interpreter shows their values
+pub fn show_cell(cell: &Cell) -> String {
+    match cell.tag as u64 >> 16 {
+        tag::STR => {
+            // Strings point into the module that created them, which has to be alive
+            let s = unsafe {
+                let header = cell.payload as *const u64;
+                std::slice::from_raw_parts(header.add(1) as *const u8, *header as usize)
+            };
+            format!("{:?}", String::from_utf8_lossy(s))
+        }
+        tag::QUOTE => format!("{{quote {:#x}}}", cell.payload),
+        tag::PTR => format!("{{pointer {:#x}}}", cell.payload),
+        tag::LIST => {
+            // A header cell holding the count precedes the elements
+            let elements = unsafe {
+                let header = cell.payload as *const Cell;
+                std::slice::from_raw_parts(header.add(1), (*header).payload as usize)
+            };
+            let elements: Vec<_> = elements.iter().map(show_cell).collect();
+            format!("[{}]", elements.join(" "))
+        }
+        _ => match cell_literal(cell) {
+            Some(n) => n.to_string(),
+            None => format!("<{:#x} {:#x}>", cell.tag, cell.payload),
+        },
+    }
+}
+
+pub struct Compiler<'ctx, 'a> {
     pub context: &'ctx Context,
     pub builder: &'a Builder<'ctx>,
     pub fpm: &'a PassManager<FunctionValue<'ctx>>,
@@ -20,7 +145,12 @@ struct Compiler<'ctx, 'a> {
 
     pub name_map: NameMap,
     pub name_exec_map: HashMap<String, FunctionValue<'ctx>>,
-    // pub main_stack_ptr:
+    // Of the integers the program can build, arithmetic and put switch over all of them
+    pub int_widths: BTreeSet<u64>,
+
+    quote_count: usize,
+    // The word being compiled and the block its calls of itself in tail position jump to
+    tail_call: Option<(String, BasicBlock<'ctx>)>,
 }
 
 impl<'ctx, 'a> Compiler<'ctx, 'a> {
@@ -33,7 +163,15 @@ impl<'ctx, 'a> Compiler<'ctx, 'a> {
         name_map: NameMap,
     ) -> Self {
         let size = name_map.len();
-        let mut stack_ptr = builder;
+
+        let mut int_widths: BTreeSet<u64> = tag::INT_WIDTHS
+            .iter()
+            .chain(tag::WIDE_WIDTHS.iter())
+            .copied()
+            .collect();
+        for node in name_map.values() {
+            node_widths(node, &mut int_widths);
+        }
 
         Self {
             context,
@@ -43,6 +181,2399 @@ impl<'ctx, 'a> Compiler<'ctx, 'a> {
 
             name_map,
             name_exec_map: HashMap::with_capacity(size),
+            int_widths,
+
+            quote_count: 0,
+            tail_call: None,
+        }
+    }
+
+    fn cell_type(&self) -> StructType<'ctx> {
+        self.context.struct_type(
+            &[
+                self.context.i32_type().into(),
+                self.context.i64_type().into(),
+            ],
+            false,
+        )
+    }
+
+    fn extern_function(
+        &self,
+        name: &str,
+        f: impl Fn() -> FunctionType<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        match self.module.get_function(name) {
+            Some(x) => x,
+            None => self.module.add_function(name, f(), Some(Linkage::External)),
+        }
+    }
+
+    // Prints the message and exits, terminates the current block
+    fn trap(&self, message: &str) {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let i32_type = self.context.i32_type();
+
+        let puts = self.extern_function("puts", || i32_type.fn_type(&[i8_ptr.into()], false));
+        let exit = self.extern_function("exit", || {
+            self.context.void_type().fn_type(&[i32_type.into()], false)
+        });
+
+        let message = self
+            .builder
+            .build_global_string_ptr(&format!("sbl: {}", message), "trap");
+        self.builder
+            .build_call(puts, &[message.as_pointer_value().into()], "");
+        self.builder
+            .build_call(exit, &[i32_type.const_int(1, false).into()], "");
+        self.builder.build_unreachable();
+    }
+
+    fn stack_slot(&self, index: IntValue<'ctx>) -> (PointerValue<'ctx>, PointerValue<'ctx>) {
+        let stack = self.module.get_global("sbl.stack").unwrap();
+        let zero = self.context.i64_type().const_zero();
+
+        let cell = unsafe {
+            self.builder
+                .build_in_bounds_gep(stack.as_pointer_value(), &[zero, index], "cell")
+        };
+
+        (
+            self.builder.build_struct_gep(cell, 0, "tag").unwrap(),
+            self.builder.build_struct_gep(cell, 1, "payload").unwrap(),
+        )
+    }
+
+    // Emits the data stack and its push and pop functions once per module
+    fn runtime(&self) {
+        if self.module.get_function("sbl.push").is_some() {
+            return;
+        }
+
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let cell_type = self.cell_type();
+        let stack_type = cell_type.array_type(STACK_SIZE as u32);
+
+        let stack = self.module.add_global(stack_type, None, "sbl.stack");
+        stack.set_initializer(&stack_type.const_zero());
+        let sp = self.module.add_global(i64_type, None, "sbl.sp");
+        sp.set_initializer(&i64_type.const_zero());
+        let sp = sp.as_pointer_value();
+
+        let push = self.module.add_function(
+            "sbl.push",
+            self.context
+                .void_type()
+                .fn_type(&[i32_type.into(), i64_type.into()], false),
+            None,
+        );
+        let entry = self.context.append_basic_block(push, "entry");
+        let fail = self.context.append_basic_block(push, "overflow");
+        let ok = self.context.append_basic_block(push, "ok");
+
+        self.builder.position_at_end(entry);
+        let index = self.builder.build_load(sp, "sp").into_int_value();
+        let overflow = self.builder.build_int_compare(
+            IntPredicate::UGE,
+            index,
+            i64_type.const_int(STACK_SIZE, false),
+            "is_overflow",
+        );
+        self.builder.build_conditional_branch(overflow, fail, ok);
+
+        self.builder.position_at_end(fail);
+        self.trap("stack overflow");
+
+        self.builder.position_at_end(ok);
+        let (tag, payload) = self.stack_slot(index);
+        self.builder
+            .build_store(tag, push.get_nth_param(0).unwrap());
+        self.builder
+            .build_store(payload, push.get_nth_param(1).unwrap());
+        let index = self
+            .builder
+            .build_int_add(index, i64_type.const_int(1, false), "sp");
+        self.builder.build_store(sp, index);
+        self.builder.build_return(None);
+
+        let pop = self
+            .module
+            .add_function("sbl.pop", cell_type.fn_type(&[], false), None);
+        let entry = self.context.append_basic_block(pop, "entry");
+        let fail = self.context.append_basic_block(pop, "underflow");
+        let ok = self.context.append_basic_block(pop, "ok");
+
+        self.builder.position_at_end(entry);
+        let index = self.builder.build_load(sp, "sp").into_int_value();
+        let underflow = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            index,
+            i64_type.const_zero(),
+            "is_underflow",
+        );
+        self.builder.build_conditional_branch(underflow, fail, ok);
+
+        self.builder.position_at_end(fail);
+        self.trap("stack underflow");
+
+        self.builder.position_at_end(ok);
+        let index = self
+            .builder
+            .build_int_sub(index, i64_type.const_int(1, false), "sp");
+        self.builder.build_store(sp, index);
+        let (tag, payload) = self.stack_slot(index);
+        let tag = self.builder.build_load(tag, "tag");
+        let payload = self.builder.build_load(payload, "payload");
+        let cell = self
+            .builder
+            .build_insert_value(cell_type.get_undef(), tag, 0, "cell")
+            .unwrap()
+            .into_struct_value();
+        let cell = self
+            .builder
+            .build_insert_value(cell, payload, 1, "cell")
+            .unwrap()
+            .into_struct_value();
+        self.builder.build_return(Some(&cell));
+    }
+
+    pub fn push(&self, tag: IntValue<'ctx>, payload: IntValue<'ctx>) {
+        let push = self.module.get_function("sbl.push").unwrap();
+
+        self.builder
+            .build_call(push, &[tag.into(), payload.into()], "");
+    }
+
+    pub fn push_const(&self, tag: u64, payload: u64) {
+        self.push(
+            self.context.i32_type().const_int(tag, false),
+            self.context.i64_type().const_int(payload, false),
+        )
+    }
+
+    // Returns the tag and the payload of the popped cell
+    pub fn pop(&self) -> (IntValue<'ctx>, IntValue<'ctx>) {
+        let pop = self.module.get_function("sbl.pop").unwrap();
+
+        let cell = self
+            .builder
+            .build_call(pop, &[], "cell")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_struct_value();
+
+        (
+            self.builder
+                .build_extract_value(cell, 0, "tag")
+                .unwrap()
+                .into_int_value(),
+            self.builder
+                .build_extract_value(cell, 1, "payload")
+                .unwrap()
+                .into_int_value(),
+        )
+    }
+
+    fn current_function(&self) -> FunctionValue<'ctx> {
+        self.builder
+            .get_insert_block()
+            .and_then(|x| x.get_parent())
+            .unwrap()
+    }
+
+    fn append_block(&self, name: &str) -> BasicBlock<'ctx> {
+        self.context
+            .append_basic_block(self.current_function(), name)
+    }
+
+    // Allocas in the entry block are promoted to registers
+    fn entry_alloca(&self, name: &str) -> PointerValue<'ctx> {
+        let builder = self.context.create_builder();
+        let entry = self.current_function().get_first_basic_block().unwrap();
+
+        match entry.get_first_instruction() {
+            Some(instruction) => builder.position_before(&instruction),
+            None => builder.position_at_end(entry),
+        }
+
+        builder.build_alloca(self.cell_type(), name)
+    }
+
+    fn load_cell(&self, slot: PointerValue<'ctx>, name: &str) -> (IntValue<'ctx>, IntValue<'ctx>) {
+        let tag = self.builder.build_struct_gep(slot, 0, "tag").unwrap();
+        let payload = self.builder.build_struct_gep(slot, 1, "payload").unwrap();
+
+        (
+            self.builder.build_load(tag, name).into_int_value(),
+            self.builder.build_load(payload, name).into_int_value(),
+        )
+    }
+
+    fn store_cell(&self, slot: PointerValue<'ctx>, tag: IntValue<'ctx>, payload: IntValue<'ctx>) {
+        self.builder
+            .build_store(self.builder.build_struct_gep(slot, 0, "tag").unwrap(), tag);
+        self.builder.build_store(
+            self.builder.build_struct_gep(slot, 1, "payload").unwrap(),
+            payload,
+        );
+    }
+
+    // The cell the pointer held by `slot` points to, offset by the popped index if any
+    fn pointed_cell(&self, slot: PointerValue<'ctx>, indexed: bool) -> PointerValue<'ctx> {
+        let (_, address) = self.load_cell(slot, "address");
+        let cell = self.builder.build_int_to_ptr(
+            address,
+            self.cell_type().ptr_type(AddressSpace::Generic),
+            "cell",
+        );
+
+        if !indexed {
+            return cell;
+        }
+
+        let (_, index) = self.pop();
+        unsafe { self.builder.build_gep(cell, &[index], "cell") }
+    }
+
+    // Zeroed heap cells, arrays are never freed
+    fn allocate_cells(&self, count: IntValue<'ctx>) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        let calloc = self.extern_function("calloc", || {
+            i8_ptr.fn_type(&[i64_type.into(), i64_type.into()], false)
+        });
+        let memory = self
+            .builder
+            .build_call(
+                calloc,
+                &[count.into(), self.cell_type().size_of().unwrap().into()],
+                "memory",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+
+        self.trap_if(
+            self.builder.build_is_null(memory, "is_null"),
+            "out of memory",
+        );
+
+        self.builder.build_ptr_to_int(memory, i64_type, "address")
+    }
+
+    // Strings are a length followed by their UTF-8 bytes and a null
+    fn string_pointer(&self, payload: IntValue<'ctx>) -> PointerValue<'ctx> {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let header = self.builder.build_int_to_ptr(payload, i8_ptr, "string");
+        let offset = self.context.i64_type().const_int(8, false);
+        unsafe { self.builder.build_gep(header, &[offset], "bytes") }
+    }
+
+    fn string_length(&self, payload: IntValue<'ctx>) -> IntValue<'ctx> {
+        let i64_ptr = self.context.i64_type().ptr_type(AddressSpace::Generic);
+        let header = self.builder.build_int_to_ptr(payload, i64_ptr, "string");
+        self.builder.build_load(header, "len").into_int_value()
+    }
+
+    fn string_byte(&self, string: PointerValue<'ctx>, index: IntValue<'ctx>) -> IntValue<'ctx> {
+        let byte = unsafe { self.builder.build_gep(string, &[index], "byte") };
+        let byte = self.builder.build_load(byte, "byte").into_int_value();
+        self.builder
+            .build_int_z_extend(byte, self.context.i64_type(), "byte")
+    }
+
+    // Continuation bytes are never the first of a code point
+    fn inside_char(&self, byte: IntValue<'ctx>) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let high = self
+            .builder
+            .build_and(byte, i64_type.const_int(0xc0, false), "high");
+        self.builder.build_int_compare(
+            IntPredicate::EQ,
+            high,
+            i64_type.const_int(0x80, false),
+            "inside_char",
+        )
+    }
+
+    // A constant global laid out like the strings built at runtime
+    fn string_literal(&self, s: &str) -> IntValue<'ctx> {
+        let i8_type = self.context.i8_type();
+
+        let bytes: Vec<_> = (s.len() as u64)
+            .to_ne_bytes()
+            .iter()
+            .chain(s.as_bytes())
+            .chain(&[0])
+            .map(|x| i8_type.const_int(*x as u64, false))
+            .collect();
+
+        let global = self
+            .module
+            .add_global(i8_type.array_type(bytes.len() as u32), None, "str");
+        global.set_initializer(&i8_type.const_array(&bytes));
+        global.set_constant(true);
+        global.set_alignment(8);
+
+        self.builder.build_ptr_to_int(
+            global.as_pointer_value(),
+            self.context.i64_type(),
+            "payload",
+        )
+    }
+
+    fn malloc(&self, size: IntValue<'ctx>) -> PointerValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        let malloc = self.extern_function("malloc", || i8_ptr.fn_type(&[i64_type.into()], false));
+        let memory = self
+            .builder
+            .build_call(malloc, &[size.into()], "memory")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+        self.trap_if(
+            self.builder.build_is_null(memory, "is_null"),
+            "out of memory",
+        );
+
+        memory
+    }
+
+    fn free(&self, memory: PointerValue<'ctx>) {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let free = self.extern_function("free", || {
+            self.context.void_type().fn_type(&[i8_ptr.into()], false)
+        });
+        self.builder.build_call(free, &[memory.into()], "");
+    }
+
+    // An uninitialized string of `len` bytes and a pointer to them
+    fn allocate_string(&self, len: IntValue<'ctx>) -> (IntValue<'ctx>, PointerValue<'ctx>) {
+        let i64_type = self.context.i64_type();
+
+        let size = self
+            .builder
+            .build_int_add(len, i64_type.const_int(9, false), "size");
+        let memory = self.malloc(size);
+        let header = self.builder.build_pointer_cast(
+            memory,
+            i64_type.ptr_type(AddressSpace::Generic),
+            "header",
+        );
+        self.builder.build_store(header, len);
+
+        let payload = self.builder.build_ptr_to_int(memory, i64_type, "address");
+        let bytes = self.string_pointer(payload);
+        let end = unsafe { self.builder.build_gep(bytes, &[len], "end") };
+        self.builder
+            .build_store(end, self.context.i8_type().const_zero());
+
+        (payload, bytes)
+    }
+
+    // The parts copied one after the other into a new string, strings are never freed
+    fn copy_string(&self, parts: &[(PointerValue<'ctx>, IntValue<'ctx>)]) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+
+        let len = parts.iter().fold(i64_type.const_zero(), |size, (_, len)| {
+            self.builder.build_int_add(size, *len, "len")
+        });
+        let (payload, bytes) = self.allocate_string(len);
+
+        let mut offset = i64_type.const_zero();
+        for (source, len) in parts {
+            let destination = unsafe { self.builder.build_gep(bytes, &[offset], "destination") };
+            self.builder
+                .build_memcpy(destination, 1, *source, 1, *len)
+                .unwrap();
+            offset = self.builder.build_int_add(offset, *len, "offset");
+        }
+
+        payload
+    }
+
+    fn trap_if(&self, condition: IntValue<'ctx>, message: &str) {
+        let fail = self.append_block("trap");
+        let ok = self.append_block("ok");
+
+        self.builder.build_conditional_branch(condition, fail, ok);
+        self.builder.position_at_end(fail);
+        self.trap(message);
+        self.builder.position_at_end(ok);
+    }
+
+    fn narrow_widths(&self) -> impl Iterator<Item = u64> + '_ {
+        self.int_widths.iter().copied().filter(|x| *x <= 64)
+    }
+
+    fn wide_widths(&self) -> impl Iterator<Item = u64> + '_ {
+        self.int_widths.iter().copied().filter(|x| *x > 64)
+    }
+
+    fn int_type(&self, width: u64) -> IntType<'ctx> {
+        match width {
+            0 => self.context.i64_type(),
+            width => self.context.custom_width_int_type(width as u32),
+        }
+    }
+
+    fn load_wide(&self, payload: IntValue<'ctx>, width: u64) -> IntValue<'ctx> {
+        let pointer = self.builder.build_int_to_ptr(
+            payload,
+            self.int_type(width).ptr_type(AddressSpace::Generic),
+            "wide",
+        );
+        self.builder.build_load(pointer, "value").into_int_value()
+    }
+
+    // Wide results are written to the heap and never freed, like strings
+    fn box_wide(&self, value: IntValue<'ctx>, width: u64) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+
+        let memory = self.malloc(i64_type.const_int((width + 63) / 64 * 8, false));
+        let pointer = self.builder.build_pointer_cast(
+            memory,
+            self.int_type(width).ptr_type(AddressSpace::Generic),
+            "wide",
+        );
+        self.builder.build_store(pointer, value);
+
+        self.builder.build_ptr_to_int(memory, i64_type, "payload")
+    }
+
+    // A constant global holding the value of a wide literal
+    fn wide_constant(&self, width: u16, value: &BigInt) -> PointerValue<'ctx> {
+        let int_type = self.int_type(width as u64);
+
+        // Two's complement words, least significant first
+        let (_, max) = int_bounds(width, false);
+        let (_, mut words) = (((value % &max) + &max) % &max).to_u64_digits();
+        words.resize((width as usize + 63) / 64, 0);
+
+        let global = self.module.add_global(int_type, None, "wide");
+        global.set_initializer(&int_type.const_int_arbitrary_precision(&words));
+        global.set_constant(true);
+
+        global.as_pointer_value()
+    }
+
+    // The tag of a literal wider than 64 bits and the address of its value
+    fn wide_literal(&self, n: &NumericLiteral) -> (u64, IntValue<'ctx>) {
+        let (kind, width, value) = match n {
+            NumericLiteral::Int(width, value) => (tag::INT, width, value),
+            NumericLiteral::Uint(width, value) => (tag::UINT, width, value),
+            x => unreachable!("{:?} is not a wide integer", x),
+        };
+
+        let address = self.wide_constant(*width, value);
+        (
+            tag::of(kind, *width as u64),
+            self.builder
+                .build_ptr_to_int(address, self.context.i64_type(), "payload"),
+        )
+    }
+
+    fn push_literal(&self, n: &NumericLiteral) {
+        match literal_cell(n) {
+            Some((tag, payload)) => self.push_const(tag, payload),
+            None => {
+                let (tag, payload) = self.wide_literal(n);
+                self.push(self.context.i32_type().const_int(tag, false), payload)
+            }
+        }
+    }
+
+    // Writes the decimal digits of a wide integer back to front, printf has no format for them
+    fn put_wide(&self, payload: IntValue<'ctx>, width: u64, signed: bool) {
+        let i8_type = self.context.i8_type();
+        let i64_type = self.context.i64_type();
+        let int_type = self.int_type(width);
+
+        let value = self.load_wide(payload, width);
+        let negative = if signed {
+            self.builder.build_int_compare(
+                IntPredicate::SLT,
+                value,
+                int_type.const_zero(),
+                "negative",
+            )
+        } else {
+            self.context.bool_type().const_zero()
+        };
+        let negated = self
+            .builder
+            .build_int_sub(int_type.const_zero(), value, "negated");
+        let magnitude = self
+            .builder
+            .build_select(negative, negated, value, "magnitude")
+            .into_int_value();
+
+        // Digits, a sign and the null
+        let size = width * 31 / 100 + 3;
+        let buffer = self.malloc(i64_type.const_int(size, false));
+        let end = i64_type.const_int(size - 1, false);
+        let null = unsafe { self.builder.build_gep(buffer, &[end], "null") };
+        self.builder.build_store(null, i8_type.const_zero());
+
+        let before = self.builder.get_insert_block().unwrap();
+        let digits = self.append_block("digits");
+        let done = self.append_block("digits_done");
+        self.builder.build_unconditional_branch(digits);
+
+        self.builder.position_at_end(digits);
+        let rest = self.builder.build_phi(int_type, "rest");
+        let index = self.builder.build_phi(i64_type, "index");
+        let rest_value = rest.as_basic_value().into_int_value();
+        let index_value = index.as_basic_value().into_int_value();
+
+        let ten = int_type.const_int(10, false);
+        let digit = self
+            .builder
+            .build_int_unsigned_rem(rest_value, ten, "digit");
+        let digit = self.builder.build_int_truncate(digit, i8_type, "digit");
+        let digit =
+            self.builder
+                .build_int_add(digit, i8_type.const_int(b'0' as u64, false), "digit");
+        let position =
+            self.builder
+                .build_int_sub(index_value, i64_type.const_int(1, false), "position");
+        let cell = unsafe { self.builder.build_gep(buffer, &[position], "digit") };
+        self.builder.build_store(cell, digit);
+
+        let next = self.builder.build_int_unsigned_div(rest_value, ten, "next");
+        let more =
+            self.builder
+                .build_int_compare(IntPredicate::NE, next, int_type.const_zero(), "more");
+        rest.add_incoming(&[(&magnitude, before), (&next, digits)]);
+        index.add_incoming(&[(&end, before), (&position, digits)]);
+        self.builder.build_conditional_branch(more, digits, done);
+
+        self.builder.position_at_end(done);
+        let sign = self
+            .builder
+            .build_int_sub(position, i64_type.const_int(1, false), "sign");
+        let cell = unsafe { self.builder.build_gep(buffer, &[sign], "sign") };
+        self.builder
+            .build_store(cell, i8_type.const_int(b'-' as u64, false));
+        let start = self
+            .builder
+            .build_select(negative, sign, position, "start")
+            .into_int_value();
+        let string = unsafe { self.builder.build_gep(buffer, &[start], "string") };
+        self.put_c_string(string);
+        self.free(buffer);
+    }
+
+    // All output goes through putchar, byte by byte since a string may hold nulls
+    fn put_bytes(&self, bytes: PointerValue<'ctx>, len: IntValue<'ctx>) {
+        let i32_type = self.context.i32_type();
+        let putchar =
+            self.extern_function("putchar", || i32_type.fn_type(&[i32_type.into()], false));
+
+        self.compile_counted_loop(self.context.i64_type().const_zero(), len, |index| {
+            let byte = self.string_byte(bytes, index);
+            let byte = self.builder.build_int_truncate(byte, i32_type, "byte");
+            self.builder.build_call(putchar, &[byte.into()], "");
+        });
+    }
+
+    fn put_string(&self, payload: IntValue<'ctx>) {
+        self.put_bytes(self.string_pointer(payload), self.string_length(payload));
+    }
+
+    fn put_c_string(&self, string: PointerValue<'ctx>) {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let strlen = self.extern_function("strlen", || {
+            self.context.i64_type().fn_type(&[i8_ptr.into()], false)
+        });
+        let len = self
+            .builder
+            .build_call(strlen, &[string.into()], "len")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+
+        self.put_bytes(string, len);
+    }
+
+    // Formats into `buffer`, which holds FORMAT_SIZE bytes
+    fn format(
+        &self,
+        buffer: PointerValue<'ctx>,
+        format: &str,
+        values: &[BasicMetadataValueEnum<'ctx>],
+    ) {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let i64_type = self.context.i64_type();
+        let snprintf = self.extern_function("snprintf", || {
+            self.context
+                .i32_type()
+                .fn_type(&[i8_ptr.into(), i64_type.into(), i8_ptr.into()], true)
+        });
+
+        let format = self.builder.build_global_string_ptr(format, "format");
+        let mut arguments = vec![
+            buffer.into(),
+            i64_type.const_int(FORMAT_SIZE, false).into(),
+            format.as_pointer_value().into(),
+        ];
+        arguments.extend_from_slice(values);
+        self.builder.build_call(snprintf, &arguments, "");
+    }
+
+    fn put_formatted(&self, format: &str, value: BasicMetadataValueEnum<'ctx>) {
+        let buffer = self.malloc(self.context.i64_type().const_int(FORMAT_SIZE, false));
+        self.format(buffer, format, &[value]);
+        self.put_c_string(buffer);
+        self.free(buffer);
+    }
+
+    // The %g with the fewest significant digits that reads back as the same value, like the
+    // interpreter writes floats, 17 digits always do
+    fn put_float(&self, value: FloatValue<'ctx>) {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let i32_type = self.context.i32_type();
+        let f64_type = self.context.f64_type();
+        let float_type = value.get_type();
+
+        // Every NaN is written as nan, whatever its sign
+        let is_nan = self
+            .builder
+            .build_float_compare(FloatPredicate::UNO, value, value, "is_nan");
+        let value = self
+            .builder
+            .build_select(is_nan, float_type.const_float(f64::NAN), value, "value")
+            .into_float_value();
+        let single = float_type == self.context.f32_type();
+        let double = if single {
+            self.builder.build_float_ext(value, f64_type, "double")
+        } else {
+            value
+        };
+
+        // Read back at the width of the value
+        let parse_name = if single { "strtof" } else { "strtod" };
+        let end = i8_ptr.ptr_type(AddressSpace::Generic);
+        let parse = self.extern_function(parse_name, || {
+            float_type.fn_type(&[i8_ptr.into(), end.into()], false)
+        });
+
+        let buffer = self.malloc(self.context.i64_type().const_int(FORMAT_SIZE, false));
+        let before = self.builder.get_insert_block().unwrap();
+        let digits = self.append_block("float_digits");
+        let done = self.append_block("float_done");
+        self.builder.build_unconditional_branch(digits);
+
+        self.builder.position_at_end(digits);
+        let precision = self.builder.build_phi(i32_type, "precision");
+        let precision_value = precision.as_basic_value().into_int_value();
+        self.format(buffer, "%.*g", &[precision_value.into(), double.into()]);
+
+        let parsed = self
+            .builder
+            .build_call(parse, &[buffer.into(), end.const_null().into()], "parsed")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_float_value();
+        let same = self
+            .builder
+            .build_float_compare(FloatPredicate::OEQ, parsed, value, "same");
+        let last = self.builder.build_int_compare(
+            IntPredicate::UGE,
+            precision_value,
+            i32_type.const_int(17, false),
+            "last",
+        );
+        let next =
+            self.builder
+                .build_int_add(precision_value, i32_type.const_int(1, false), "next");
+        precision.add_incoming(&[(&i32_type.const_int(1, false), before), (&next, digits)]);
+        let stop = self.builder.build_or(same, last, "stop");
+        self.builder.build_conditional_branch(stop, done, digits);
+
+        self.builder.position_at_end(done);
+        self.put_c_string(buffer);
+        self.free(buffer);
+    }
+
+    // Quotations are given the closure they were called through
+    fn quote_type(&self) -> FunctionType<'ctx> {
+        let i64_ptr = self.context.i64_type().ptr_type(AddressSpace::Generic);
+        self.context.void_type().fn_type(&[i64_ptr.into()], false)
+    }
+
+    fn call_quote(&self, tag: IntValue<'ctx>, payload: IntValue<'ctx>) {
+        let i32_type = self.context.i32_type();
+
+        let kind =
+            self.builder
+                .build_right_shift(tag, i32_type.const_int(16, false), false, "kind");
+        let not_quote = self.builder.build_int_compare(
+            IntPredicate::NE,
+            kind,
+            i32_type.const_int(tag::QUOTE, false),
+            "not_quote",
+        );
+        self.trap_if(not_quote, "called a value that is not a quotation");
+
+        let i64_ptr = self.context.i64_type().ptr_type(AddressSpace::Generic);
+        let closure = self.builder.build_int_to_ptr(payload, i64_ptr, "closure");
+        let function = self
+            .builder
+            .build_load(closure, "function")
+            .into_int_value();
+        let quote = self.builder.build_int_to_ptr(
+            function,
+            self.quote_type().ptr_type(AddressSpace::Generic),
+            "quote",
+        );
+        self.builder.build_call(
+            CallableValue::try_from(quote).unwrap(),
+            &[closure.into()],
+            "",
+        );
+    }
+
+    // Quotation values point to their function followed by the slots of the locals they captured
+    fn allocate_closure(
+        &self,
+        function: FunctionValue<'ctx>,
+        captured: &[PointerValue<'ctx>],
+    ) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+
+        let memory = self.malloc(i64_type.const_int(8 * (captured.len() as u64 + 1), false));
+        let closure = self.builder.build_pointer_cast(
+            memory,
+            i64_type.ptr_type(AddressSpace::Generic),
+            "closure",
+        );
+
+        let function = self.builder.build_ptr_to_int(
+            function.as_global_value().as_pointer_value(),
+            i64_type,
+            "function",
+        );
+        self.builder.build_store(closure, function);
+        for (i, slot) in captured.iter().enumerate() {
+            let field = unsafe {
+                self.builder.build_gep(
+                    closure,
+                    &[i64_type.const_int(i as u64 + 1, false)],
+                    "captured",
+                )
+            };
+            let address = self.builder.build_ptr_to_int(*slot, i64_type, "address");
+            self.builder.build_store(field, address);
+        }
+
+        self.builder.build_ptr_to_int(memory, i64_type, "payload")
+    }
+
+    // Slots of locals captured by a quotation are on the heap, they may outlive the body
+    fn local_slot(&self, name: &str, captured: bool) -> PointerValue<'ctx> {
+        if !captured {
+            return self.entry_alloca(name);
+        }
+
+        let address = self.allocate_cells(self.context.i64_type().const_int(1, false));
+        self.builder.build_int_to_ptr(
+            address,
+            self.cell_type().ptr_type(AddressSpace::Generic),
+            name,
+        )
+    }
+
+    fn compile_put(&self, tag: IntValue<'ctx>, payload: IntValue<'ctx>) {
+        let i32_type = self.context.i32_type();
+        let done = self.append_block("done");
+        let unknown = self.append_block("unknown");
+
+        let int = self.append_block("int");
+        let uint = self.append_block("uint");
+        let float32 = self.append_block("float32");
+        let float64 = self.append_block("float64");
+        let string = self.append_block("str");
+
+        let mut cases = Vec::new();
+        for width in self.narrow_widths() {
+            cases.push((tag::of(tag::INT, width), int));
+            cases.push((tag::of(tag::UINT, width), uint));
+        }
+        cases.push((tag::of(tag::BOOL, 1), uint));
+        cases.push((tag::of(tag::FLOAT, 32), float32));
+        cases.push((tag::of(tag::FLOAT, 64), float64));
+        cases.push((tag::of(tag::STR, 8), string));
+
+        let mut wide = Vec::new();
+        for kind in [tag::INT, tag::UINT].iter() {
+            for width in self.wide_widths() {
+                let block = self.append_block("wide");
+                cases.push((tag::of(*kind, width), block));
+                wide.push((block, width, *kind == tag::INT));
+            }
+        }
+
+        let cases: Vec<_> = cases
+            .into_iter()
+            .map(|(tag, block)| (i32_type.const_int(tag, false), block))
+            .collect();
+        self.builder.build_switch(tag, unknown, &cases);
+
+        self.builder.position_at_end(int);
+        self.put_formatted("%lld", payload.into());
+        self.builder.build_unconditional_branch(done);
+
+        self.builder.position_at_end(uint);
+        self.put_formatted("%llu", payload.into());
+        self.builder.build_unconditional_branch(done);
+
+        self.builder.position_at_end(float32);
+        let value = self.builder.build_int_truncate(payload, i32_type, "bits");
+        let value = self
+            .builder
+            .build_bitcast(value, self.context.f32_type(), "value")
+            .into_float_value();
+        self.put_float(value);
+        self.builder.build_unconditional_branch(done);
+
+        self.builder.position_at_end(float64);
+        let value = self
+            .builder
+            .build_bitcast(payload, self.context.f64_type(), "value")
+            .into_float_value();
+        self.put_float(value);
+        self.builder.build_unconditional_branch(done);
+
+        self.builder.position_at_end(string);
+        self.put_string(payload);
+        self.builder.build_unconditional_branch(done);
+
+        for (block, width, signed) in wide {
+            self.builder.position_at_end(block);
+            self.put_wide(payload, width, signed);
+            self.builder.build_unconditional_branch(done);
+        }
+
+        self.builder.position_at_end(unknown);
+        self.trap("value is not writeable");
+
+        self.builder.position_at_end(done);
+    }
+
+    // Like the interpreter, any number but zero is true, NaN included, other values trap
+    fn truthy(&self, tag: IntValue<'ctx>, payload: IntValue<'ctx>) -> IntValue<'ctx> {
+        let i32_type = self.context.i32_type();
+        let check = self.builder.get_insert_block().unwrap();
+        let done = self.append_block("truthy_done");
+        let unknown = self.append_block("not_a_condition");
+        let mut cases = Vec::new();
+        let mut results = Vec::new();
+
+        // The payload of a narrow integer or a boolean is zero exactly when its value is
+        let narrow = self.append_block("narrow");
+        for kind in [tag::INT, tag::UINT].iter() {
+            for width in self.narrow_widths() {
+                cases.push((tag::of(*kind, width), narrow));
+            }
+        }
+        cases.push((tag::of(tag::BOOL, 1), narrow));
+        self.builder.position_at_end(narrow);
+        let zero = self.context.i64_type().const_zero();
+        results.push((
+            self.builder
+                .build_int_compare(IntPredicate::NE, payload, zero, "truthy"),
+            narrow,
+        ));
+        self.builder.build_unconditional_branch(done);
+
+        for kind in [tag::INT, tag::UINT].iter() {
+            for width in self.wide_widths() {
+                let block = self.append_block("wide");
+                cases.push((tag::of(*kind, width), block));
+                self.builder.position_at_end(block);
+
+                let value = self.load_wide(payload, width);
+                let zero = self.int_type(width).const_zero();
+                results.push((
+                    self.builder
+                        .build_int_compare(IntPredicate::NE, value, zero, "truthy"),
+                    block,
+                ));
+                self.builder.build_unconditional_branch(done);
+            }
+        }
+
+        for width in [32, 64].iter() {
+            let block = self.append_block("float");
+            cases.push((tag::of(tag::FLOAT, *width), block));
+            self.builder.position_at_end(block);
+
+            let value = if *width == 32 {
+                let bits = self.builder.build_int_truncate(payload, i32_type, "bits");
+                self.builder
+                    .build_bitcast(bits, self.context.f32_type(), "value")
+            } else {
+                self.builder
+                    .build_bitcast(payload, self.context.f64_type(), "value")
+            }
+            .into_float_value();
+            let zero = value.get_type().const_zero();
+            results.push((
+                self.builder
+                    .build_float_compare(FloatPredicate::UNE, value, zero, "truthy"),
+                block,
+            ));
+            self.builder.build_unconditional_branch(done);
+        }
+
+        self.builder.position_at_end(unknown);
+        self.trap("value can not be used as a condition");
+
+        self.builder.position_at_end(check);
+        let cases: Vec<_> = cases
+            .into_iter()
+            .map(|(tag, block)| (i32_type.const_int(tag, false), block))
+            .collect();
+        self.builder.build_switch(tag, unknown, &cases);
+
+        self.builder.position_at_end(done);
+        let truthy = self.builder.build_phi(self.context.bool_type(), "truthy");
+        for (value, block) in results.iter() {
+            truthy.add_incoming(&[(value, *block)]);
+        }
+
+        truthy.as_basic_value().into_int_value()
+    }
+
+    // Emits one block per operand type, each pushing its result and branching to done
+    fn compile_binary(&self, builtin: Builtin) {
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let bool_tag = i32_type.const_int(tag::of(tag::BOOL, 1), false);
+
+        let (tb, pb) = self.pop();
+        let (ta, pa) = self.pop();
+
+        let mismatch = self
+            .builder
+            .build_int_compare(IntPredicate::NE, ta, tb, "mismatch");
+        self.trap_if(
+            mismatch,
+            &format!("operands of {} differ in type", builtin.name()),
+        );
+        let check = self.builder.get_insert_block().unwrap();
+
+        let done = self.append_block("done");
+        let unknown = self.append_block("unknown");
+        let mut cases = Vec::new();
+
+        for kind in [tag::INT, tag::UINT].iter() {
+            let signed = *kind == tag::INT;
+
+            for width in self.int_widths.iter() {
+                let block = self.append_block("int");
+                cases.push((i32_type.const_int(tag::of(*kind, *width), false), block));
+                self.builder.position_at_end(block);
+
+                let int_type = self.int_type(*width);
+                let (a, b) = if *width > 64 {
+                    (self.load_wide(pa, *width), self.load_wide(pb, *width))
+                } else {
+                    (
+                        self.builder
+                            .build_int_truncate_or_bit_cast(pa, int_type, "a"),
+                        self.builder
+                            .build_int_truncate_or_bit_cast(pb, int_type, "b"),
+                    )
+                };
+
+                let predicate = match (builtin, signed) {
+                    (Builtin::Eq, _) => Some(IntPredicate::EQ),
+                    (Builtin::Neq, _) => Some(IntPredicate::NE),
+                    (Builtin::Gt, true) => Some(IntPredicate::SGT),
+                    (Builtin::Gt, false) => Some(IntPredicate::UGT),
+                    (Builtin::Lt, true) => Some(IntPredicate::SLT),
+                    (Builtin::Lt, false) => Some(IntPredicate::ULT),
+                    _ => None,
+                };
+
+                if let Some(predicate) = predicate {
+                    let result = self.builder.build_int_compare(predicate, a, b, "result");
+                    let result = self.builder.build_int_z_extend(result, i64_type, "result");
+                    self.push(bool_tag, result);
+                } else {
+                    let result = match builtin {
+                        Builtin::Add => self.builder.build_int_add(a, b, "result"),
+                        Builtin::Sub => self.builder.build_int_sub(a, b, "result"),
+                        Builtin::Mul => self.builder.build_int_mul(a, b, "result"),
+                        _ => {
+                            let zero = self.builder.build_int_compare(
+                                IntPredicate::EQ,
+                                b,
+                                int_type.const_zero(),
+                                "is_zero",
+                            );
+                            self.trap_if(zero, "division by zero");
+
+                            if signed {
+                                // MIN / -1 overflows, dividing by -1 is negating instead
+                                let minus_one = self.builder.build_int_compare(
+                                    IntPredicate::EQ,
+                                    b,
+                                    int_type.const_all_ones(),
+                                    "is_minus_one",
+                                );
+                                let divisor = self
+                                    .builder
+                                    .build_select(minus_one, int_type.const_int(1, false), b, "b")
+                                    .into_int_value();
+                                let result =
+                                    self.builder.build_int_signed_div(a, divisor, "result");
+                                let negated = self.builder.build_int_sub(
+                                    int_type.const_zero(),
+                                    result,
+                                    "negated",
+                                );
+                                self.builder
+                                    .build_select(minus_one, negated, result, "result")
+                                    .into_int_value()
+                            } else {
+                                self.builder.build_int_unsigned_div(a, b, "result")
+                            }
+                        }
+                    };
+
+                    let result = if *width > 64 {
+                        self.box_wide(result, *width)
+                    } else if signed {
+                        self.builder
+                            .build_int_s_extend_or_bit_cast(result, i64_type, "result")
+                    } else {
+                        self.builder
+                            .build_int_z_extend_or_bit_cast(result, i64_type, "result")
+                    };
+                    self.push(ta, result);
+                }
+
+                self.builder.build_unconditional_branch(done);
+            }
+        }
+
+        for width in tag::FLOAT_WIDTHS.iter() {
+            let block = self.append_block("float");
+            cases.push((
+                i32_type.const_int(tag::of(tag::FLOAT, *width), false),
+                block,
+            ));
+            self.builder.position_at_end(block);
+
+            let (bits_type, float_type) = if *width == 32 {
+                (i32_type, self.context.f32_type())
+            } else {
+                (i64_type, self.context.f64_type())
+            };
+            let a = self
+                .builder
+                .build_int_truncate_or_bit_cast(pa, bits_type, "a");
+            let a = self
+                .builder
+                .build_bitcast(a, float_type, "a")
+                .into_float_value();
+            let b = self
+                .builder
+                .build_int_truncate_or_bit_cast(pb, bits_type, "b");
+            let b = self
+                .builder
+                .build_bitcast(b, float_type, "b")
+                .into_float_value();
+
+            let predicate = match builtin {
+                Builtin::Eq => Some(FloatPredicate::OEQ),
+                Builtin::Neq => Some(FloatPredicate::UNE),
+                Builtin::Gt => Some(FloatPredicate::OGT),
+                Builtin::Lt => Some(FloatPredicate::OLT),
+                _ => None,
+            };
+
+            if let Some(predicate) = predicate {
+                let result = self.builder.build_float_compare(predicate, a, b, "result");
+                let result = self.builder.build_int_z_extend(result, i64_type, "result");
+                self.push(bool_tag, result);
+            } else {
+                let result = match builtin {
+                    Builtin::Add => self.builder.build_float_add(a, b, "result"),
+                    Builtin::Sub => self.builder.build_float_sub(a, b, "result"),
+                    Builtin::Mul => self.builder.build_float_mul(a, b, "result"),
+                    _ => self.builder.build_float_div(a, b, "result"),
+                };
+                let result = self
+                    .builder
+                    .build_bitcast(result, bits_type, "result")
+                    .into_int_value();
+                let result = self
+                    .builder
+                    .build_int_z_extend_or_bit_cast(result, i64_type, "result");
+                self.push(ta, result);
+            }
+
+            self.builder.build_unconditional_branch(done);
+        }
+
+        if let Builtin::Eq | Builtin::Neq = builtin {
+            let predicate = if builtin == Builtin::Eq {
+                IntPredicate::EQ
+            } else {
+                IntPredicate::NE
+            };
+
+            let block = self.append_block("bool");
+            cases.push((bool_tag, block));
+            self.builder.position_at_end(block);
+            let result = self.builder.build_int_compare(predicate, pa, pb, "result");
+            let result = self.builder.build_int_z_extend(result, i64_type, "result");
+            self.push(bool_tag, result);
+            self.builder.build_unconditional_branch(done);
+
+            let block = self.append_block("str");
+            cases.push((i32_type.const_int(tag::of(tag::STR, 8), false), block));
+            self.builder.position_at_end(block);
+            let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+            let memcmp = self.extern_function("memcmp", || {
+                i32_type.fn_type(&[i8_ptr.into(), i8_ptr.into(), i64_type.into()], false)
+            });
+            let len = self.string_length(pa);
+            let same_len = self.builder.build_int_compare(
+                IntPredicate::EQ,
+                len,
+                self.string_length(pb),
+                "same_len",
+            );
+            // Nothing is compared past the end of the shorter string
+            let len = self
+                .builder
+                .build_select(same_len, len, i64_type.const_zero(), "len")
+                .into_int_value();
+            let a = self.string_pointer(pa);
+            let b = self.string_pointer(pb);
+            let order = self
+                .builder
+                .build_call(memcmp, &[a.into(), b.into(), len.into()], "order")
+                .try_as_basic_value()
+                .left()
+                .unwrap()
+                .into_int_value();
+            let same_bytes = self.builder.build_int_compare(
+                IntPredicate::EQ,
+                order,
+                i32_type.const_zero(),
+                "same_bytes",
+            );
+            let result = self.builder.build_and(same_len, same_bytes, "equal");
+            let result = if builtin == Builtin::Eq {
+                result
+            } else {
+                self.builder.build_not(result, "result")
+            };
+            let result = self.builder.build_int_z_extend(result, i64_type, "result");
+            self.push(bool_tag, result);
+            self.builder.build_unconditional_branch(done);
+        }
+
+        self.builder.position_at_end(check);
+        self.builder.build_switch(ta, unknown, &cases);
+
+        self.builder.position_at_end(unknown);
+        self.trap(&format!("can not apply {} to its operands", builtin.name()));
+
+        self.builder.position_at_end(done);
+    }
+
+    // Runs the body with every counter from start up to but not including end
+    fn compile_counted_loop(
+        &self,
+        start: IntValue<'ctx>,
+        end: IntValue<'ctx>,
+        body: impl Fn(IntValue<'ctx>),
+    ) {
+        let i64_type = self.context.i64_type();
+        let before = self.builder.get_insert_block().unwrap();
+        let check = self.append_block("loop");
+        let run = self.append_block("loop_body");
+        let done = self.append_block("loop_done");
+
+        self.builder.build_unconditional_branch(check);
+        self.builder.position_at_end(check);
+        let counter = self.builder.build_phi(i64_type, "counter");
+        let counter_value = counter.as_basic_value().into_int_value();
+        let more = self
+            .builder
+            .build_int_compare(IntPredicate::SLT, counter_value, end, "more");
+        self.builder.build_conditional_branch(more, run, done);
+
+        self.builder.position_at_end(run);
+        body(counter_value);
+        let next = self
+            .builder
+            .build_int_add(counter_value, i64_type.const_int(1, false), "next");
+        let latch = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(check);
+
+        counter.add_incoming(&[(&start, before), (&next, latch)]);
+        self.builder.position_at_end(done);
+    }
+
+    fn compile_builtin(&self, builtin: Builtin) {
+        use Builtin::*;
+        match builtin {
+            Dup => {
+                let (tag, payload) = self.pop();
+                self.push(tag, payload);
+                self.push(tag, payload);
+            }
+            Drop => {
+                self.pop();
+            }
+            Swap => {
+                let (tb, pb) = self.pop();
+                let (ta, pa) = self.pop();
+                self.push(tb, pb);
+                self.push(ta, pa);
+            }
+            Pick => {
+                let i64_type = self.context.i64_type();
+                let (_, index) = self.pop();
+
+                let sp = self.module.get_global("sbl.sp").unwrap();
+                let sp = self
+                    .builder
+                    .build_load(sp.as_pointer_value(), "sp")
+                    .into_int_value();
+                let out_of_range =
+                    self.builder
+                        .build_int_compare(IntPredicate::UGE, index, sp, "out_of_range");
+                self.trap_if(out_of_range, "pick out of range");
+
+                let index = self.builder.build_int_sub(sp, index, "index");
+                let index =
+                    self.builder
+                        .build_int_sub(index, i64_type.const_int(1, false), "index");
+                let (tag, payload) = self.stack_slot(index);
+                let tag = self.builder.build_load(tag, "tag").into_int_value();
+                let payload = self.builder.build_load(payload, "payload").into_int_value();
+                self.push(tag, payload);
+            }
+            Call => {
+                let (tag, payload) = self.pop();
+                self.call_quote(tag, payload);
+            }
+            Put => {
+                let (tag, payload) = self.pop();
+                self.compile_put(tag, payload);
+            }
+            If | Else => {
+                let (qt, qp) = self.pop();
+                let (ct, cp) = self.pop();
+
+                let run = self.append_block("run");
+                let done = self.append_block("done");
+
+                let truthy = self.truthy(ct, cp);
+                if builtin == If {
+                    self.builder.build_conditional_branch(truthy, run, done);
+                } else {
+                    self.builder.build_conditional_branch(truthy, done, run);
+                }
+
+                self.builder.position_at_end(run);
+                self.call_quote(qt, qp);
+                self.builder.build_unconditional_branch(done);
+
+                self.builder.position_at_end(done);
+                self.push(ct, cp);
+            }
+            Add | Sub | Mul | Div | Eq | Neq | Gt | Lt => self.compile_binary(builtin),
+            StrLen => {
+                let (_, payload) = self.pop();
+                self.push(
+                    self.context
+                        .i32_type()
+                        .const_int(tag::of(tag::INT, 0), false),
+                    self.string_length(payload),
+                );
+            }
+            StrCat => {
+                let (_, pb) = self.pop();
+                let (_, pa) = self.pop();
+                let a = (self.string_pointer(pa), self.string_length(pa));
+                let b = (self.string_pointer(pb), self.string_length(pb));
+
+                let result = self.copy_string(&[a, b]);
+                self.push(
+                    self.context
+                        .i32_type()
+                        .const_int(tag::of(tag::STR, 8), false),
+                    result,
+                );
+            }
+            StrSlice => {
+                let (_, end) = self.pop();
+                let (_, start) = self.pop();
+                let (_, payload) = self.pop();
+                let string = self.string_pointer(payload);
+                let len = self.string_length(payload);
+
+                let end_out =
+                    self.builder
+                        .build_int_compare(IntPredicate::UGT, end, len, "end_out");
+                let start_out =
+                    self.builder
+                        .build_int_compare(IntPredicate::UGT, start, end, "start_out");
+                let out_of_bounds = self.builder.build_or(start_out, end_out, "out_of_bounds");
+                self.trap_if(out_of_bounds, "strslice out of bounds");
+
+                // The null after the last byte is never inside a character
+                let split = self.builder.build_or(
+                    self.inside_char(self.string_byte(string, start)),
+                    self.inside_char(self.string_byte(string, end)),
+                    "split",
+                );
+                self.trap_if(split, "strslice inside a character");
+
+                let count = self.builder.build_int_sub(end, start, "count");
+                let source = unsafe { self.builder.build_gep(string, &[start], "source") };
+                let result = self.copy_string(&[(source, count)]);
+                self.push(
+                    self.context
+                        .i32_type()
+                        .const_int(tag::of(tag::STR, 8), false),
+                    result,
+                );
+            }
+            NextChar => {
+                let i64_type = self.context.i64_type();
+                let (_, offset) = self.pop();
+                let (_, payload) = self.pop();
+                let string = self.string_pointer(payload);
+
+                let out_of_bounds = self.builder.build_int_compare(
+                    IntPredicate::UGE,
+                    offset,
+                    self.string_length(payload),
+                    "out_of_bounds",
+                );
+                self.trap_if(out_of_bounds, "nextchar out of bounds");
+                let lead = self.string_byte(string, offset);
+                self.trap_if(self.inside_char(lead), "nextchar inside a character");
+
+                // The lead byte gives the length and holds the highest bits
+                let (len, mask) = [(0xf0, 3, 0x0f), (0xe0, 2, 0x1f), (0x80, 1, 0x7f)]
+                    .iter()
+                    .fold(
+                        (
+                            i64_type.const_int(4, false),
+                            i64_type.const_int(0x07, false),
+                        ),
+                        |(len, mask), (bound, shorter, wider)| {
+                            let below = self.builder.build_int_compare(
+                                IntPredicate::ULT,
+                                lead,
+                                i64_type.const_int(*bound, false),
+                                "below",
+                            );
+                            (
+                                self.builder
+                                    .build_select(
+                                        below,
+                                        i64_type.const_int(*shorter, false),
+                                        len,
+                                        "len",
+                                    )
+                                    .into_int_value(),
+                                self.builder
+                                    .build_select(
+                                        below,
+                                        i64_type.const_int(*wider, false),
+                                        mask,
+                                        "mask",
+                                    )
+                                    .into_int_value(),
+                            )
+                        },
+                    );
+
+                let code_tag = self
+                    .context
+                    .i32_type()
+                    .const_int(tag::of(tag::UINT, 32), false);
+                let slot = self.entry_alloca("code");
+                self.store_cell(slot, code_tag, self.builder.build_and(lead, mask, "code"));
+
+                // Each continuation byte adds six bits
+                self.compile_counted_loop(i64_type.const_int(1, false), len, |index| {
+                    let index = self.builder.build_int_add(offset, index, "index");
+                    let bits = self.builder.build_and(
+                        self.string_byte(string, index),
+                        i64_type.const_int(0x3f, false),
+                        "bits",
+                    );
+                    let (_, code) = self.load_cell(slot, "code");
+                    let code =
+                        self.builder
+                            .build_left_shift(code, i64_type.const_int(6, false), "code");
+                    let code = self.builder.build_or(code, bits, "code");
+                    self.store_cell(slot, code_tag, code);
+                });
+
+                let (tag, code) = self.load_cell(slot, "code");
+                self.push(tag, code);
+                self.push(
+                    self.context
+                        .i32_type()
+                        .const_int(tag::of(tag::INT, 0), false),
+                    self.builder.build_int_add(offset, len, "next"),
+                );
+            }
+            CharStr => {
+                let i64_type = self.context.i64_type();
+                let (_, code) = self.pop();
+
+                let compare = |predicate, bound| {
+                    self.builder.build_int_compare(
+                        predicate,
+                        code,
+                        i64_type.const_int(bound, false),
+                        "compare",
+                    )
+                };
+                let surrogate = self.builder.build_and(
+                    compare(IntPredicate::UGE, 0xd800),
+                    compare(IntPredicate::ULE, 0xdfff),
+                    "surrogate",
+                );
+                let invalid = self.builder.build_or(
+                    surrogate,
+                    compare(IntPredicate::UGT, 0x10ffff),
+                    "invalid",
+                );
+                self.trap_if(invalid, "charstr of an invalid code point");
+
+                // The length and the marker bits of the lead byte
+                let (len, marker) = [(0x10000, 3, 0xe0), (0x800, 2, 0xc0), (0x80, 1, 0x00)]
+                    .iter()
+                    .fold(
+                        (
+                            i64_type.const_int(4, false),
+                            i64_type.const_int(0xf0, false),
+                        ),
+                        |(len, marker), (bound, shorter, lead)| {
+                            let below = compare(IntPredicate::ULT, *bound);
+                            (
+                                self.builder
+                                    .build_select(
+                                        below,
+                                        i64_type.const_int(*shorter, false),
+                                        len,
+                                        "len",
+                                    )
+                                    .into_int_value(),
+                                self.builder
+                                    .build_select(
+                                        below,
+                                        i64_type.const_int(*lead, false),
+                                        marker,
+                                        "marker",
+                                    )
+                                    .into_int_value(),
+                            )
+                        },
+                    );
+
+                let (payload, bytes) = self.allocate_string(len);
+                self.compile_counted_loop(i64_type.const_zero(), len, |index| {
+                    // Six bits for every byte after this one
+                    let after = self.builder.build_int_sub(len, index, "after");
+                    let after =
+                        self.builder
+                            .build_int_sub(after, i64_type.const_int(1, false), "after");
+                    let shift =
+                        self.builder
+                            .build_int_mul(after, i64_type.const_int(6, false), "shift");
+                    let bits = self.builder.build_right_shift(code, shift, false, "bits");
+
+                    let first = self.builder.build_int_compare(
+                        IntPredicate::EQ,
+                        index,
+                        i64_type.const_zero(),
+                        "first",
+                    );
+                    let lead = self.builder.build_or(marker, bits, "lead");
+                    let continuation = self.builder.build_and(
+                        bits,
+                        i64_type.const_int(0x3f, false),
+                        "continuation",
+                    );
+                    let continuation = self.builder.build_or(
+                        continuation,
+                        i64_type.const_int(0x80, false),
+                        "continuation",
+                    );
+                    let byte = self
+                        .builder
+                        .build_select(first, lead, continuation, "byte")
+                        .into_int_value();
+                    let byte =
+                        self.builder
+                            .build_int_truncate(byte, self.context.i8_type(), "byte");
+
+                    let cell = unsafe { self.builder.build_gep(bytes, &[index], "byte") };
+                    self.builder.build_store(cell, byte);
+                });
+
+                self.push(
+                    self.context
+                        .i32_type()
+                        .const_int(tag::of(tag::STR, 8), false),
+                    payload,
+                );
+            }
+            Times => {
+                let (qt, qp) = self.pop();
+                let (_, count) = self.pop();
+
+                self.compile_counted_loop(self.context.i64_type().const_zero(), count, |_| {
+                    self.call_quote(qt, qp)
+                });
+            }
+            For => {
+                let (qt, qp) = self.pop();
+                let (_, end) = self.pop();
+                let (_, start) = self.pop();
+                let index_tag = self
+                    .context
+                    .i32_type()
+                    .const_int(tag::of(tag::INT, 0), false);
+
+                self.compile_counted_loop(start, end, |index| {
+                    self.push(index_tag, index);
+                    self.call_quote(qt, qp)
+                });
+            }
+            Count => {
+                let (_, payload) = self.pop();
+                let header = self.builder.build_int_to_ptr(
+                    payload,
+                    self.cell_type().ptr_type(AddressSpace::Generic),
+                    "header",
+                );
+                let (tag, count) = self.load_cell(header, "count");
+                self.push(tag, count);
+            }
+            At => {
+                let i64_type = self.context.i64_type();
+                let (_, index) = self.pop();
+                let (_, payload) = self.pop();
+                let header = self.builder.build_int_to_ptr(
+                    payload,
+                    self.cell_type().ptr_type(AddressSpace::Generic),
+                    "header",
+                );
+
+                let (_, count) = self.load_cell(header, "count");
+                let out_of_bounds = self.builder.build_int_compare(
+                    IntPredicate::UGE,
+                    index,
+                    count,
+                    "out_of_bounds",
+                );
+                self.trap_if(out_of_bounds, "at out of bounds");
+
+                let offset =
+                    self.builder
+                        .build_int_add(index, i64_type.const_int(1, false), "offset");
+                let cell = unsafe { self.builder.build_gep(header, &[offset], "element") };
+                let (tag, payload) = self.load_cell(cell, "element");
+                self.push(tag, payload);
+            }
+            While => {
+                let (bt, bp) = self.pop();
+                let (ct, cp) = self.pop();
+                let check = self.append_block("loop");
+                let run = self.append_block("loop_body");
+                let done = self.append_block("loop_done");
+
+                self.builder.build_unconditional_branch(check);
+                self.builder.position_at_end(check);
+                self.call_quote(ct, cp);
+                let (flag_tag, flag) = self.pop();
+                let truthy = self.truthy(flag_tag, flag);
+                self.builder.build_conditional_branch(truthy, run, done);
+
+                self.builder.position_at_end(run);
+                self.call_quote(bt, bp);
+                self.builder.build_unconditional_branch(check);
+
+                self.builder.position_at_end(done);
+            }
+        }
+    }
+
+    fn finish_function(
+        &self,
+        function: FunctionValue<'ctx>,
+    ) -> anyhow::Result<FunctionValue<'ctx>> {
+        if function.verify(true) {
+            self.fpm.run_on(&function);
+
+            Ok(function)
+        } else {
+            unsafe {
+                function.delete();
+            }
+
+            bail!("Generated invalid function {:?}", function.get_name())
+        }
+    }
+
+    fn declare_word(&mut self, name: &str) -> FunctionValue<'ctx> {
+        if let Some(function) = self.name_exec_map.get(name) {
+            return *function;
+        }
+
+        let function = self.module.add_function(
+            &format!("sbl.word.{}", name),
+            self.context.void_type().fn_type(&[], false),
+            None,
+        );
+        self.name_exec_map.insert(name.to_string(), function);
+
+        function
+    }
+
+    // The captured locals are read from the closure in the order of `captured`
+    fn compile_quote(
+        &mut self,
+        body: &[Spanned<ASTNode>],
+        captured: &[String],
+    ) -> anyhow::Result<FunctionValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let block = self.builder.get_insert_block();
+
+        self.quote_count += 1;
+        let function = self.module.add_function(
+            &format!("sbl.quote.{}", self.quote_count),
+            self.quote_type(),
+            None,
+        );
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+
+        let closure = function.get_nth_param(0).unwrap().into_pointer_value();
+        let mut locals = HashMap::new();
+        for (i, name) in captured.iter().enumerate() {
+            let field = unsafe {
+                self.builder.build_gep(
+                    closure,
+                    &[i64_type.const_int(i as u64 + 1, false)],
+                    "captured",
+                )
+            };
+            let address = self.builder.build_load(field, name).into_int_value();
+            let slot = self.builder.build_int_to_ptr(
+                address,
+                self.cell_type().ptr_type(AddressSpace::Generic),
+                name,
+            );
+            locals.insert(name.clone(), slot);
+        }
+
+        // A quotation calling the word runs in a function of its own
+        let tail_call = self.tail_call.take();
+        let result = self.compile_body_in(body, locals);
+        self.tail_call = tail_call;
+        result?;
+        self.builder.build_return(None);
+
+        if let Some(block) = block {
+            self.builder.position_at_end(block);
+        }
+
+        self.finish_function(function)
+    }
+
+    pub fn compile_body(&mut self, body: &[Spanned<ASTNode>]) -> anyhow::Result<()> {
+        self.compile_body_in(body, HashMap::new())
+    }
+
+    // Locals are visible to the rest of the body they are declared in and to its quotations
+    fn compile_body_in(
+        &mut self,
+        body: &[Spanned<ASTNode>],
+        mut locals: HashMap<String, PointerValue<'ctx>>,
+    ) -> anyhow::Result<()> {
+        let i64_type = self.context.i64_type();
+
+        let mut captured = HashSet::new();
+        quoted_names(body, &mut captured);
+
+        let (body, tail_block) = match &self.tail_call {
+            Some((name, block)) => match strip_self_tail_call(body, name) {
+                Some(init) => (init, Some(*block)),
+                None => (body, None),
+            },
+            None => (body, None),
+        };
+
+        for Spanned { node, span } in body {
+            match node {
+                ASTNode::NumericLiteral(n) => self.push_literal(n),
+                ASTNode::StringLiteral(s) => {
+                    let payload = self.string_literal(s);
+
+                    self.push(
+                        self.context
+                            .i32_type()
+                            .const_int(tag::of(tag::STR, 8), false),
+                        payload,
+                    )
+                }
+                ASTNode::Ident(name) if locals.contains_key(name.as_str()) => {
+                    let (tag, payload) = self.load_cell(locals[name.as_str()], name);
+                    self.push(tag, payload)
+                }
+                ASTNode::Dec(name)
+                | ASTNode::DecTyped(name, _)
+                | ASTNode::DecPointer(name)
+                | ASTNode::Assign(name) => {
+                    let slot = match locals.get(name.as_str()) {
+                        Some(slot) => *slot,
+                        None if matches!(node, ASTNode::Assign(_)) => {
+                            return Err(Diagnostic::new(
+                                *span,
+                                format!("Assignment to undeclared variable {}", name),
+                            )
+                            .into())
+                        }
+                        None => {
+                            let slot = self.local_slot(name, captured.contains(name));
+                            locals.insert(name.clone(), slot);
+                            slot
+                        }
+                    };
+
+                    let (tag, payload) = self.pop();
+                    self.store_cell(slot, tag, payload);
+                }
+                ASTNode::DecArraySized(name, _) | ASTNode::DecArrayVariable(name) => {
+                    let count = match node {
+                        ASTNode::DecArraySized(_, size) => {
+                            i64_type.const_int(size.as_index().unwrap_or(0) as u64, false)
+                        }
+                        _ => self.pop().1,
+                    };
+                    let address = self.allocate_cells(count);
+
+                    let slot = match locals.get(name.as_str()) {
+                        Some(slot) => *slot,
+                        None => self.local_slot(name, captured.contains(name)),
+                    };
+                    locals.insert(name.clone(), slot);
+
+                    self.store_cell(
+                        slot,
+                        self.context
+                            .i32_type()
+                            .const_int(tag::of(tag::PTR, 64), false),
+                        address,
+                    );
+                }
+                ASTNode::Address(name)
+                | ASTNode::ReadAddress(name)
+                | ASTNode::ReadIndex(name)
+                | ASTNode::PointerAssign(name)
+                | ASTNode::IndexAssign(name) => {
+                    let slot = match locals.get(name.as_str()) {
+                        Some(slot) => *slot,
+                        None => {
+                            return Err(Diagnostic::new(
+                                *span,
+                                format!("Unknown variable {}", name),
+                            )
+                            .into())
+                        }
+                    };
+
+                    match node {
+                        ASTNode::Address(_) => self.push(
+                            self.context
+                                .i32_type()
+                                .const_int(tag::of(tag::PTR, 64), false),
+                            self.builder.build_ptr_to_int(slot, i64_type, "address"),
+                        ),
+                        ASTNode::ReadAddress(_) | ASTNode::ReadIndex(_) => {
+                            let cell =
+                                self.pointed_cell(slot, matches!(node, ASTNode::ReadIndex(_)));
+                            let (tag, payload) = self.load_cell(cell, name);
+                            self.push(tag, payload)
+                        }
+                        _ => {
+                            let cell =
+                                self.pointed_cell(slot, matches!(node, ASTNode::IndexAssign(_)));
+                            let (tag, payload) = self.pop();
+                            self.store_cell(cell, tag, payload)
+                        }
+                    }
+                }
+                ASTNode::Ident(name) => match self.name_exec_map.get(name) {
+                    Some(function) => {
+                        self.builder.build_call(*function, &[], "");
+                    }
+                    None => {
+                        return Err(Diagnostic::new(*span, format!("Unknown word {}", name)).into())
+                    }
+                },
+                ASTNode::Curly(body) => {
+                    let mut referenced = HashSet::new();
+                    referenced_names(body, &mut referenced);
+                    let mut names: Vec<String> = locals
+                        .keys()
+                        .filter(|x| referenced.contains(*x))
+                        .cloned()
+                        .collect();
+                    names.sort();
+
+                    let quote = self.compile_quote(body, &names)?;
+                    let slots: Vec<_> = names.iter().map(|x| locals[x]).collect();
+                    let payload = self.allocate_closure(quote, &slots);
+
+                    self.push(
+                        self.context
+                            .i32_type()
+                            .const_int(tag::of(tag::QUOTE, 64), false),
+                        payload,
+                    )
+                }
+                ASTNode::Square(items) => {
+                    let sp = self.module.get_global("sbl.sp").unwrap().as_pointer_value();
+                    let start = self.builder.build_load(sp, "start").into_int_value();
+
+                    // The elements are the cells the items leave on the stack
+                    let tail_call = self.tail_call.take();
+                    let result = self.compile_body_in(items, locals.clone());
+                    self.tail_call = tail_call;
+                    result?;
+
+                    let end = self.builder.build_load(sp, "end").into_int_value();
+                    let shrunk =
+                        self.builder
+                            .build_int_compare(IntPredicate::ULT, end, start, "shrunk");
+                    self.trap_if(shrunk, "list literal popped values from outside it");
+                    let count = self.builder.build_int_sub(end, start, "count");
+
+                    let size =
+                        self.builder
+                            .build_int_add(count, i64_type.const_int(1, false), "size");
+                    let address = self.allocate_cells(size);
+                    let header = self.builder.build_int_to_ptr(
+                        address,
+                        self.cell_type().ptr_type(AddressSpace::Generic),
+                        "header",
+                    );
+                    self.store_cell(
+                        header,
+                        self.context
+                            .i32_type()
+                            .const_int(tag::of(tag::INT, 0), false),
+                        count,
+                    );
+
+                    let stack = self.module.get_global("sbl.stack").unwrap();
+                    let source = unsafe {
+                        self.builder.build_in_bounds_gep(
+                            stack.as_pointer_value(),
+                            &[i64_type.const_zero(), start],
+                            "source",
+                        )
+                    };
+                    let elements = unsafe {
+                        self.builder
+                            .build_gep(header, &[i64_type.const_int(1, false)], "elements")
+                    };
+                    let bytes = self.builder.build_int_mul(
+                        count,
+                        self.cell_type().size_of().unwrap(),
+                        "bytes",
+                    );
+                    self.builder
+                        .build_memcpy(elements, 8, source, 8, bytes)
+                        .unwrap();
+                    self.builder.build_store(sp, start);
+
+                    self.push(
+                        self.context
+                            .i32_type()
+                            .const_int(tag::of(tag::LIST, 64), false),
+                        address,
+                    )
+                }
+            }
+        }
+
+        // Whatever the caller emits after the body is left unreachable
+        if let Some(block) = tail_block {
+            self.builder.build_unconditional_branch(block);
+            let after = self.append_block("after_tail_call");
+            self.builder.position_at_end(after);
+        }
+
+        Ok(())
+    }
+
+    // Each clause runs if every one of its patterns matches the cell at its depth
+    fn compile_clauses(&mut self, name: &str, clauses: &[Clause]) -> anyhow::Result<()> {
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let sp = self.module.get_global("sbl.sp").unwrap().as_pointer_value();
+
+        let done = self.append_block("done");
+
+        for clause in clauses {
+            let next = self.append_block("next_clause");
+
+            for (depth, patterns) in clause.patterns() {
+                let size = self.builder.build_load(sp, "sp").into_int_value();
+                let deep_enough = self.builder.build_int_compare(
+                    IntPredicate::UGT,
+                    size,
+                    i64_type.const_int(depth as u64, false),
+                    "deep_enough",
+                );
+                let check = self.append_block("pattern");
+                self.builder
+                    .build_conditional_branch(deep_enough, check, next);
+                self.builder.position_at_end(check);
+
+                let index = self.builder.build_int_sub(
+                    size,
+                    i64_type.const_int(depth as u64 + 1, false),
+                    "index",
+                );
+                let (tag, payload) = self.stack_slot(index);
+                let tag = self.builder.build_load(tag, "tag").into_int_value();
+                let payload = self.builder.build_load(payload, "payload").into_int_value();
+
+                let mut matched = self.context.bool_type().const_zero();
+                for pattern in patterns {
+                    let (pattern_tag, pattern_payload) = match literal_cell(pattern) {
+                        Some((tag, payload)) => (tag, i64_type.const_int(payload, false)),
+                        None => self.wide_literal(pattern),
+                    };
+                    let same_tag = self.builder.build_int_compare(
+                        IntPredicate::EQ,
+                        tag,
+                        i32_type.const_int(pattern_tag, false),
+                        "same_tag",
+                    );
+
+                    // Wide values are compared behind their payloads, a cell of another
+                    // type has no value to load and is compared with the pattern instead
+                    let same_payload = if literal_cell(pattern).is_none() {
+                        let width = pattern_tag & 0xffff;
+                        let compared = self
+                            .builder
+                            .build_select(same_tag, payload, pattern_payload, "compared")
+                            .into_int_value();
+                        self.builder.build_int_compare(
+                            IntPredicate::EQ,
+                            self.load_wide(compared, width),
+                            self.load_wide(pattern_payload, width),
+                            "same_payload",
+                        )
+                    } else {
+                        self.builder.build_int_compare(
+                            IntPredicate::EQ,
+                            payload,
+                            pattern_payload,
+                            "same_payload",
+                        )
+                    };
+                    let same = self.builder.build_and(same_tag, same_payload, "same");
+                    matched = self.builder.build_or(matched, same, "matched");
+                }
+
+                let following = self.append_block("pattern_matched");
+                self.builder
+                    .build_conditional_branch(matched, following, next);
+                self.builder.position_at_end(following);
+            }
+
+            self.compile_body(&clause.implementation)?;
+            self.builder.build_unconditional_branch(done);
+            self.builder.position_at_end(next);
+        }
+
+        self.trap(&format!("no clause of {} matches the stack", name));
+        self.builder.position_at_end(done);
+
+        Ok(())
+    }
+
+    pub fn compile_word(&mut self, name: &str) -> anyhow::Result<FunctionValue<'ctx>> {
+        let function = self.declare_word(name);
+        let node = match self.name_map.remove(name) {
+            Some(node) => node,
+            None => bail!("Unknown word {}", name),
+        };
+
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+
+        // Locals are allocated in the entry block, so tail calls start over after it
+        let start = self.append_block("start");
+        self.builder.build_unconditional_branch(start);
+        self.builder.position_at_end(start);
+        self.tail_call = Some((name.to_string(), start));
+
+        let result = match &node {
+            NameMapNode::Word { implementation, .. } => self.compile_body(implementation),
+            NameMapNode::Overloaded { clauses, .. } => self.compile_clauses(name, clauses),
+            NameMapNode::AliasedWord(target, _) => match self.name_exec_map.get(target) {
+                Some(target) => {
+                    self.builder.build_call(*target, &[], "");
+                    Ok(())
+                }
+                None => Err(anyhow::anyhow!("Alias {} of unknown word {}", name, target)),
+            },
+            NameMapNode::StringConst(s) => self.compile_body(&[Spanned::new(
+                ASTNode::StringLiteral(s.clone()),
+                Span::default(),
+            )]),
+            NameMapNode::NumericConst(n) => {
+                self.push_literal(n);
+                Ok(())
+            }
+            NameMapNode::Builtin { builtin, .. } => {
+                self.compile_builtin(*builtin);
+                Ok(())
+            }
+        };
+        self.tail_call = None;
+        self.name_map.insert(name.to_string(), node);
+        result?;
+
+        self.builder.build_return(None);
+
+        self.finish_function(function)
+    }
+
+    // Compiles a body that is not part of the name map, such as a repl line
+    pub fn compile_expression(
+        &mut self,
+        name: &str,
+        body: &[Spanned<ASTNode>],
+    ) -> anyhow::Result<FunctionValue<'ctx>> {
+        self.runtime();
+
+        let function =
+            self.module
+                .add_function(name, self.context.void_type().fn_type(&[], false), None);
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+
+        self.compile_body(body)?;
+        self.builder.build_return(None);
+
+        self.finish_function(function)
+    }
+
+    pub fn compile(&mut self) -> anyhow::Result<()> {
+        self.runtime();
+
+        let names: Vec<String> = CallGraph::new(&self.name_map)
+            .topological_order()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect();
+
+        for name in names.iter() {
+            self.declare_word(name);
+        }
+        for name in names.iter() {
+            self.compile_word(name)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The widths of the integer literals of a body, a value of any other width can not be built
+pub fn literal_widths(body: &[Spanned<ASTNode>], out: &mut BTreeSet<u64>) {
+    for Spanned { node, .. } in body {
+        match node {
+            ASTNode::Curly(body) | ASTNode::Square(body) => literal_widths(body, out),
+            ASTNode::NumericLiteral(n) => out.extend(integer_width(n)),
+            _ => (),
+        }
+    }
+}
+
+fn integer_width(n: &NumericLiteral) -> Option<u64> {
+    match n {
+        NumericLiteral::Int(width, _) | NumericLiteral::Uint(width, _) => Some(*width as u64),
+        _ => None,
+    }
+}
+
+fn node_widths(node: &NameMapNode, out: &mut BTreeSet<u64>) {
+    match node {
+        NameMapNode::Word { implementation, .. } => literal_widths(implementation, out),
+        NameMapNode::Overloaded { clauses, .. } => {
+            for clause in clauses {
+                literal_widths(&clause.implementation, out);
+                for (_, literals) in clause.patterns() {
+                    out.extend(literals.iter().filter_map(integer_width));
+                }
+            }
+        }
+        NameMapNode::NumericConst(n) => out.extend(integer_width(n)),
+        _ => (),
+    }
+}
+
+// Every name a body refers to, including in its quotations, whether it is a local or a word
+fn referenced_names(body: &[Spanned<ASTNode>], out: &mut HashSet<String>) {
+    for Spanned { node, .. } in body {
+        match node {
+            ASTNode::Curly(body) | ASTNode::Square(body) => referenced_names(body, out),
+            ASTNode::Ident(name)
+            | ASTNode::Dec(name)
+            | ASTNode::DecTyped(name, _)
+            | ASTNode::DecPointer(name)
+            | ASTNode::DecArraySized(name, _)
+            | ASTNode::DecArrayVariable(name)
+            | ASTNode::Assign(name)
+            | ASTNode::Address(name)
+            | ASTNode::ReadAddress(name)
+            | ASTNode::ReadIndex(name)
+            | ASTNode::PointerAssign(name)
+            | ASTNode::IndexAssign(name) => {
+                out.insert(name.clone());
+            }
+            ASTNode::NumericLiteral(_) | ASTNode::StringLiteral(_) => (),
+        }
+    }
+}
+
+// Names referenced by the quotations of a body, including those built inside its lists
+fn quoted_names(body: &[Spanned<ASTNode>], out: &mut HashSet<String>) {
+    for Spanned { node, .. } in body {
+        match node {
+            ASTNode::Curly(quote) => referenced_names(quote, out),
+            ASTNode::Square(items) => quoted_names(items, out),
+            _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use inkwell::{
+        context::Context,
+        module::Module,
+        passes::PassManager,
+        values::{AnyValue, FunctionValue},
+        OptimizationLevel,
+    };
+
+    use super::{show_cell, Cell, Compiler};
+    use crate::{
+        ast::ASTNode,
+        interpreter::Interpreter,
+        namemap::NameMap,
+        span::{Span, Spanned},
+    };
+
+    thread_local! {
+        static OUTPUT: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+    }
+
+    // Stands in for putchar, which all compiled output goes through
+    extern "C" fn capture(c: i32) -> i32 {
+        OUTPUT.with(|x| x.borrow_mut().push(c as u8));
+        c
+    }
+
+    fn load(program: &str) -> NameMap {
+        let program = crate::tokenizer::tokenizer(program.to_string()).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        crate::namemap::extract_name_map(program).unwrap()
+    }
+
+    // The words of the program and sbl.expr, which calls main
+    fn compile<'ctx>(context: &'ctx Context, program: NameMap) -> Module<'ctx> {
+        let module = context.create_module("test");
+        let builder = context.create_builder();
+        let fpm: PassManager<FunctionValue> = PassManager::create(&module);
+        fpm.initialize();
+
+        let main = Spanned::new(ASTNode::Ident("main".to_string()), Span::default());
+        let mut compiler = Compiler::new(context, &builder, &fpm, &module, program);
+        compiler.compile().unwrap();
+        compiler.compile_expression("sbl.expr", &[main]).unwrap();
+
+        assert!(module.verify().is_ok());
+        module
+    }
+
+    // The stack left by main and everything it wrote, compiled and interpreted alike
+    fn run(program: &str) -> (Vec<String>, String) {
+        let name_map = load(program);
+
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::with_output(&name_map, Box::new(&mut output));
+        interpreter.call_word("main").unwrap();
+        let stack: Vec<_> = interpreter.stack.iter().map(|x| x.to_string()).collect();
+        drop(interpreter);
+        let expected = (stack, String::from_utf8(output).unwrap());
+
+        let context = Context::create();
+        let module = compile(&context, name_map);
+        let engine = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        if let Some(putchar) = module.get_function("putchar") {
+            engine.add_global_mapping(&putchar, capture as *const () as usize);
+        }
+
+        OUTPUT.with(|x| x.borrow_mut().clear());
+        let stack = unsafe {
+            engine
+                .get_function::<unsafe extern "C" fn()>("sbl.expr")
+                .unwrap()
+                .call();
+
+            let stack = engine.get_global_address("sbl.stack").unwrap() as *const Cell;
+            let sp = engine.get_global_address("sbl.sp").unwrap() as *const u64;
+            (0..sp.read() as usize)
+                .map(|i| show_cell(&stack.add(i).read()))
+                .collect()
+        };
+        let output = OUTPUT.with(|x| String::from_utf8(x.take()).unwrap());
+
+        let result = (stack, output);
+        assert_eq!(result, expected, "{}", program);
+        result
+    }
+
+    #[test]
+    fn it_compiles_words() {
+        let (stack, output) = run("@two 2 @greet { \"Hello world!\" . }
+                                   @main { 1 two { greet } @ 3.5d . 1.1f . 1E20d . }");
+
+        assert_eq!(stack, vec!["1i", "2i"]);
+        assert_eq!(output, "Hello world!3.51.11e+20");
+    }
+
+    #[test]
+    fn it_compiles_loops() {
+        let program = "?down [-Int@0 +Int] @down { }
+                       ?down [-Int +Int] @down { 1 - down }
+                       @main { 0 3 { 1 + } times 0 0 5 { + } for
+                               1 { dup 9 < } { 2 * } while 5 down }";
+
+        let context = Context::create();
+        let module = compile(&context, load(program));
+        let down = module.get_function("sbl.word.down").unwrap();
+        assert!(!down
+            .print_to_string()
+            .to_string()
+            .contains("call void @sbl.word.down"));
+
+        let (stack, _) = run(program);
+        assert_eq!(stack, vec!["3i", "10i", "16i", "0i"]);
+    }
+
+    #[test]
+    fn it_tests_conditions_by_type() {
+        let (stack, _) = run("@main {
+            0u128 { 1 } if drop -0.0d { 2 } if drop 0.0f { 3 } if drop 2.5f { 4 } if drop
+            1u256 { 5 } if drop 0i128 { 6 } else drop 3i128 { dup } { 1i128 - } while
+        }");
+
+        assert_eq!(stack, vec!["4i", "5i", "6i", "0i128"]);
+    }
+
+    #[test]
+    fn it_compiles_closures() {
+        let (stack, _) = run("@adder { ::n { n + } }
+                              @main { 5 adder ::add5 1 add5 @
+                                      0 ::count 3 { count 1 + :count } times count
+                                      10 adder ::add10 2 add10 @ 3 add5 @ }");
+
+        assert_eq!(stack, vec!["6i", "3i", "12i", "8i"]);
+    }
+
+    #[test]
+    fn it_compiles_wide_integers() {
+        let (stack, output) = run("?big [-U256@1E70u256 +Bool] @big { drop 1b }
+                                   ?big [-U256 +Bool] @big { drop 0b }
+                                   @main { 1E70u256 ::x x x * . -5i128 2i128 / . x big .
+                                           x 1u256 - big -5i128 }");
+
+        assert_eq!(stack, vec!["0b", "-5i128"]);
+        assert!(output.starts_with("195215971164638006587517087989736404878791186"));
+        assert!(output.ends_with("-21"));
+    }
+
+    #[test]
+    fn it_compiles_any_integer_width() {
+        let (stack, output) = run("@main { 60i7 5i7 + . 1u24 2u24 - . 3u100 2u100 * ::x x .
+                                           x 6u100 = -1i100 ::y y 1i100 - y { 1 } if drop
+                                           0u100 { 2 } else drop }");
+
+        assert_eq!(stack, vec!["1b", "-2i100", "1i", "2i"]);
+        assert_eq!(output, "-63167772156");
+    }
+
+    #[test]
+    fn it_divides_by_minus_one() {
+        let (stack, output) = run("@main { -9223372036854775808 -1 / . -128i8 -1i8 / .
+                                           -64i7 -1i7 / . 7i128 -1i128 / -1 -1 / }");
+
+        assert_eq!(stack, vec!["-7i128", "1i"]);
+        assert_eq!(output, "-9223372036854775808-128-64");
+    }
+
+    #[test]
+    fn it_compiles_strings() {
+        let (stack, output) = run(
+            "@main { \"aλ\\0\" ::s s len . s 1 nextchar . . '😀' charstr s =
+                     s 0 1 substr \"b\" concat . s { . } eachchar s \"aλ\" \"\\0\" concat = }",
+        );
+
+        assert_eq!(stack, vec!["0b", "1b"]);
+        assert_eq!(output, "43955ab979550");
+    }
+
+    #[test]
+    fn it_compiles_lists() {
+        let (stack, _) = run(
+            "@main { 2 ::x [1 x [\"a\"] { x + }] ::l l size l 0 at l 2 at
+                                      l 3 at ::f 4 f @ 0 ::k l { drop k 1 + :k } foreach k
+                                      [] size }",
+        );
+
+        assert_eq!(stack, vec!["4i", "1i", "[\"a\"]", "6i", "4i", "0i"]);
+    }
+}
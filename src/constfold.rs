@@ -0,0 +1,123 @@
+use crate::{
+    ast::ASTNode,
+    builtins::{numeric_binary, Builtin},
+    span::Spanned,
+};
+
+const FOLDABLE: [Builtin; 8] = [
+    Builtin::Add,
+    Builtin::Sub,
+    Builtin::Mul,
+    Builtin::Div,
+    Builtin::Eq,
+    Builtin::Neq,
+    Builtin::Gt,
+    Builtin::Lt,
+];
+
+fn fold_node(Spanned { node, span }: Spanned<ASTNode>) -> Spanned<ASTNode> {
+    let node = match node {
+        ASTNode::Curly(body) => ASTNode::Curly(fold_constants(body)),
+        ASTNode::Square(body) => ASTNode::Square(fold_constants(body)),
+        x => x,
+    };
+
+    Spanned::new(node, span)
+}
+
+// Replaces two literals followed by arithmetic or a comparison with their result, which wraps
+// around like at runtime, operations the runtime would fail on are left to fail there
+pub fn fold_constants(body: Vec<Spanned<ASTNode>>) -> Vec<Spanned<ASTNode>> {
+    let mut out: Vec<Spanned<ASTNode>> = Vec::with_capacity(body.len());
+
+    for node in body {
+        let node = fold_node(node);
+
+        let builtin = match &node.node {
+            ASTNode::Ident(name) => FOLDABLE.iter().find(|x| x.name() == name),
+            _ => None,
+        };
+        let folded = match (builtin, out.as_slice()) {
+            (
+                Some(builtin),
+                [.., Spanned {
+                    node: ASTNode::NumericLiteral(a),
+                    span: start,
+                }, Spanned {
+                    node: ASTNode::NumericLiteral(b),
+                    ..
+                }],
+            ) => numeric_binary(*builtin, a, b)
+                .ok()
+                .map(|result| Spanned::new(ASTNode::NumericLiteral(result), start.to(node.span))),
+            _ => None,
+        };
+
+        match folded {
+            Some(folded) => {
+                out.truncate(out.len() - 2);
+                out.push(folded);
+            }
+            None => out.push(node),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::ASTNode;
+    use crate::numeric_litteral::NumericLiteral::*;
+
+    use super::fold_constants;
+
+    fn fold(program: &str) -> Vec<ASTNode> {
+        let program = crate::tokenizer::tokenizer(program.to_string()).unwrap();
+        let program = crate::ast::build_expression(program).unwrap();
+
+        fold_constants(program)
+            .into_iter()
+            .map(|x| x.node)
+            .collect()
+    }
+
+    #[test]
+    fn it_folds_literals() {
+        let body = fold("1 2 3 + * 10 4 - 2u8 3u8 > x 1 +");
+
+        assert!(matches!(body[0], ASTNode::NumericLiteral(SysInt(5))));
+        assert!(matches!(body[1], ASTNode::NumericLiteral(SysInt(6))));
+        assert!(matches!(body[2], ASTNode::NumericLiteral(Boolean(false))));
+        assert!(matches!(&body[3], ASTNode::Ident(x) if x == "x"));
+        assert!(matches!(body[4], ASTNode::NumericLiteral(SysInt(1))));
+        assert!(matches!(&body[5], ASTNode::Ident(x) if x == "+"));
+
+        let body = fold("{ [1 1 +] 2 2 * }");
+        assert!(matches!(&body[0], ASTNode::Curly(x) if x.len() == 2));
+    }
+
+    #[test]
+    fn it_wraps_like_the_runtime() {
+        for (program, expected) in [
+            ("200u8 100u8 +", "44u8"),
+            ("1u 2u -", "18446744073709551615u"),
+            ("100i8 2i8 *", "-56i8"),
+            ("9223372036854775807 1 +", "-9223372036854775808i"),
+        ]
+        .iter()
+        {
+            let body = fold(program);
+            assert!(
+                matches!(&body[..], [ASTNode::NumericLiteral(x)] if x.to_string() == *expected),
+                "{} folds to {:?}",
+                program,
+                body
+            );
+        }
+
+        // Left for the runtime to report
+        assert_eq!(fold("1 0 /").len(), 3);
+        assert_eq!(fold("1 1u +").len(), 3);
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+use crate::{error::SblError, modules::ModuleError, span::Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+        }
+    }
+
+    // error: message
+    //  --> path:line:column
+    //   |
+    // 3 | @main { foo }
+    //   |         ^^^
+    pub fn render(&self, source: &str, path: &str) -> String {
+        self.render_as("error", source, path)
+    }
+
+    pub fn render_warning(&self, source: &str, path: &str) -> String {
+        self.render_as("warning", source, path)
+    }
+
+    fn render_as(&self, level: &str, source: &str, path: &str) -> String {
+        let line = source
+            .lines()
+            .nth(self.span.line.saturating_sub(1))
+            .unwrap_or("");
+        let number = self.span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        let prefix: String = line
+            .chars()
+            .take(self.span.column.saturating_sub(1))
+            .map(|x| if x == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let line_start = source
+            .lines()
+            .take(self.span.line.saturating_sub(1))
+            .map(|x| x.len() + 1)
+            .sum::<usize>();
+        let line_end = line_start + line.len();
+        let width = source
+            .get(self.span.start.max(line_start)..self.span.end.min(line_end))
+            .map_or(1, |x| x.chars().count().max(1));
+
+        format!(
+            "{}: {}\n{} --> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+            level,
+            self.message,
+            gutter,
+            path,
+            self.span.line,
+            self.span.column,
+            gutter,
+            number,
+            line,
+            gutter,
+            prefix,
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+// Attaches a location to errors which don't carry one yet
+pub fn locate<T>(result: anyhow::Result<T>, span: Span) -> anyhow::Result<T> {
+    result.map_err(|err| {
+        if err.is::<Diagnostic>() || err.is::<SblError>() {
+            err
+        } else {
+            Diagnostic::new(span, err.to_string()).into()
+        }
+    })
+}
+
+// The files of a program by the index their spans carry, the first is the one being run
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    files: Vec<(String, String)>,
+}
+
+impl Sources {
+    pub fn new(path: &str, source: &str) -> Self {
+        Self {
+            files: vec![(path.to_string(), source.to_string())],
+        }
+    }
+
+    pub fn add(&mut self, path: &str, source: &str) -> usize {
+        self.files.push((path.to_string(), source.to_string()));
+        self.files.len() - 1
+    }
+
+    // The path and source of a file, spans from elsewhere are shown against the first one
+    pub fn get(&self, file: usize) -> (&str, &str) {
+        match self.files.get(file).or_else(|| self.files.first()) {
+            Some((path, source)) => (path, source),
+            None => ("", ""),
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let (path, source) = self.get(diagnostic.span.file);
+        diagnostic.render(source, path)
+    }
+
+    pub fn render_warning(&self, diagnostic: &Diagnostic) -> String {
+        let (path, source) = self.get(diagnostic.span.file);
+        diagnostic.render_warning(source, path)
+    }
+
+    // Renders located errors against their file and falls back to the plain message
+    pub fn render_error(&self, err: &anyhow::Error) -> String {
+        if let Some(diagnostic) = err.downcast_ref::<Diagnostic>() {
+            self.render(diagnostic)
+        } else if let Some(err) = err.downcast_ref::<SblError>() {
+            self.render(&err.diagnostic())
+        } else if let Some(err) = err.downcast_ref::<ModuleError>() {
+            err.render()
+        } else {
+            format!("error: {}", err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Diagnostic;
+    use crate::span::Span;
+
+    #[test]
+    fn it_renders_carets() {
+        let source = "@main {\n    1 foo .\n}";
+        let diagnostic = Diagnostic::new(
+            Span {
+                start: 14,
+                end: 17,
+                line: 2,
+                column: 7,
+                file: 0,
+            },
+            "Unknown word foo",
+        );
+
+        assert_eq!(
+            diagnostic.render(source, "main.sbl"),
+            "error: Unknown word foo\n  --> main.sbl:2:7\n  |\n2 |     1 foo .\n  |       ^^^"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+
+use crate::{diagnostic::Diagnostic, span::Span};
+
+// Every way the front end can reject its input
+#[derive(Debug, Clone, PartialEq)]
+pub enum SblError {
+    // Tokenizer
+    UnknownEscape(char, Span),
+    InvalidEscape(String, Span),
+    UnterminatedString(Span),
+    UnterminatedChar(Span),
+    EmptyChar(Span),
+
+    // Folding
+    UnmatchedBracket(char, Span),
+    MismatchedBracket {
+        found: char,
+        open: char,
+        opened_at: Span,
+        span: Span,
+    },
+    UnclosedBracket(char, Span),
+
+    // Parsing
+    UnexpectedToken(String, Span),
+    UnexpectedEof(Span),
+    InvalidTopLevel(String, Span),
+    MissingWordName(Span),
+    MissingWordBody(String, Span),
+    MalformedTyping(Span),
+    MalformedImport(Span),
+
+    // Literals
+    InvalidLiteral {
+        literal: String,
+        reason: String,
+        span: Span,
+    },
+    UnimplementedLiteral(String, Span),
+
+    // Types
+    InvalidTypeName(String, Span),
+    InvalidTypeVariable(String, Span),
+    EmptyType(Span),
+    ExplicitNonVariable(String, Span),
+    UnprefixedType(String, Span),
+    MalformedPattern(Span),
+
+    // Name map
+    UntypeableWord(String, Span),
+    DoubleTyping(String, Span),
+    UndeclaredTyping(String, Span),
+    BadDeclaration(Span),
+    Redefinition(String, Span),
+    UntypedClause(String, Span),
+    UndefinedWord(String, Span),
+    UndefinedAliasTarget(String, String, Span),
+    CyclicAlias(String, Span),
+
+    // Modules
+    ImportNotFound(String, Span),
+    CyclicImport(String, Span),
+    UnresolvedImport(String, Span),
+}
+
+impl SblError {
+    pub fn span(&self) -> Span {
+        use SblError::*;
+        match self {
+            UnknownEscape(_, span)
+            | InvalidEscape(_, span)
+            | UnterminatedString(span)
+            | UnterminatedChar(span)
+            | EmptyChar(span)
+            | UnmatchedBracket(_, span)
+            | MismatchedBracket { span, .. }
+            | UnclosedBracket(_, span)
+            | UnexpectedToken(_, span)
+            | UnexpectedEof(span)
+            | InvalidTopLevel(_, span)
+            | MissingWordName(span)
+            | MissingWordBody(_, span)
+            | MalformedTyping(span)
+            | MalformedImport(span)
+            | InvalidLiteral { span, .. }
+            | UnimplementedLiteral(_, span)
+            | InvalidTypeName(_, span)
+            | InvalidTypeVariable(_, span)
+            | EmptyType(span)
+            | ExplicitNonVariable(_, span)
+            | UnprefixedType(_, span)
+            | MalformedPattern(span)
+            | UntypeableWord(_, span)
+            | DoubleTyping(_, span)
+            | UndeclaredTyping(_, span)
+            | BadDeclaration(span)
+            | Redefinition(_, span)
+            | UntypedClause(_, span)
+            | UndefinedWord(_, span)
+            | UndefinedAliasTarget(_, _, span)
+            | CyclicAlias(_, span)
+            | ImportNotFound(_, span)
+            | CyclicImport(_, span)
+            | UnresolvedImport(_, span) => *span,
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.span(), self.to_string())
+    }
+}
+
+impl fmt::Display for SblError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SblError::*;
+        match self {
+            UnknownEscape(x, _) => write!(f, "Unknown escape \\{}", x),
+            InvalidEscape(x, _) => write!(f, "Invalid escape, {}", x),
+            UnterminatedString(_) => write!(f, "Unterminated string literal"),
+            UnterminatedChar(_) => write!(f, "Unterminated char literal"),
+            EmptyChar(_) => write!(f, "Empty char literal"),
+
+            UnmatchedBracket(x, _) => write!(f, "Unmatched {}", x),
+            MismatchedBracket {
+                found,
+                open,
+                opened_at,
+                ..
+            } => write!(
+                f,
+                "Mismatched {}, the {} opened at {}:{} is still open",
+                found, open, opened_at.line, opened_at.column
+            ),
+            UnclosedBracket(x, _) => write!(f, "Unclosed {}", x),
+
+            UnexpectedToken(x, _) => write!(f, "Unexpected {}", x),
+            UnexpectedEof(_) => write!(f, "Unexpected EOF"),
+            InvalidTopLevel(x, _) => write!(f, "Invalid top-level deceleration {}", x),
+            MissingWordName(_) => write!(f, "Word deceleration must be followed by ident"),
+            MissingWordBody(x, _) => write!(f, "Word {} has no body", x),
+            MalformedTyping(_) => write!(f, "Typing must be followed by ident and bracket"),
+            MalformedImport(_) => write!(f, "use must be followed by a path string"),
+
+            InvalidLiteral {
+                literal, reason, ..
+            } => write!(f, "Invalid literal {}: {}", literal, reason),
+            UnimplementedLiteral(x, _) => write!(f, "Unimplemented literal {}", x),
+
+            InvalidTypeName(x, _) => write!(f, "Invalid type name {:?}", x),
+            InvalidTypeVariable(x, _) => write!(f, "Invalid type variable {:?}", x),
+            EmptyType(_) => write!(f, "Empty type"),
+            ExplicitNonVariable(x, _) => {
+                write!(f, "Only type variables can be explicit, {:?} is not", x)
+            }
+            UnprefixedType(x, _) => write!(f, "Type {:?} must be prefixed by + or -", x),
+            MalformedPattern(_) => write!(
+                f,
+                "Patterns must be popped literals like -a@1 or -a@(1 | 2)"
+            ),
+
+            UntypeableWord(x, _) => {
+                write!(f, "Only words with a body can be typed, {} is not", x)
+            }
+            DoubleTyping(x, _) => write!(f, "Word {} is typed twice without a declaration", x),
+            UndeclaredTyping(x, _) => write!(f, "Typing for undeclared word {}", x),
+            BadDeclaration(_) => write!(f, "Bad word declaration value"),
+            Redefinition(x, _) => write!(f, "Word {} is already defined", x),
+            UntypedClause(x, _) => write!(
+                f,
+                "Every clause of the overloaded word {} needs a ?{} [...] signature",
+                x, x
+            ),
+            UndefinedWord(x, _) => write!(f, "Word {} is called but never defined", x),
+            UndefinedAliasTarget(x, target, _) => {
+                write!(f, "Alias {} points at undefined word {}", x, target)
+            }
+            CyclicAlias(x, _) => write!(f, "Alias {} refers back to itself", x),
+
+            ImportNotFound(x, _) => write!(f, "Cannot read the imported file {}", x),
+            CyclicImport(x, _) => write!(f, "Importing {} creates a cycle", x),
+            UnresolvedImport(x, _) => write!(f, "{} can only be imported from a file", x),
+        }
+    }
+}
+
+impl std::error::Error for SblError {}
@@ -0,0 +1,571 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+use anyhow::bail;
+use num_traits::{ToPrimitive, Zero};
+
+use crate::{
+    ast::ASTNode,
+    callgraph::strip_self_tail_call,
+    diagnostic::locate,
+    namemap::{NameMap, NameMapNode},
+    numeric_litteral::NumericLiteral,
+    span::Spanned,
+};
+
+// Cells of a local or an array, pointers hold an offset into them
+pub type Memory = Rc<RefCell<Vec<Value>>>;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Numeric(NumericLiteral),
+    Str(String),
+    Quote(Rc<Closure>),
+    Ptr(Memory, usize),
+    List(Rc<Vec<Value>>),
+}
+
+// A quotation shares the cells of the locals it can see with the body that declared them
+#[derive(Debug)]
+pub struct Closure {
+    pub body: Vec<Spanned<ASTNode>>,
+    pub captured: HashMap<String, Memory>,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Numeric(n) => write!(f, "{}", n.to_string()),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Quote(closure) => write!(f, "{{quote of {} nodes}}", closure.body.len()),
+            Value::Ptr(memory, offset) => {
+                write!(f, "{{pointer {}/{}}}", offset, memory.borrow().len())
+            }
+            Value::List(elements) => {
+                let elements: Vec<_> = elements.iter().map(|x| x.to_string()).collect();
+                write!(f, "[{}]", elements.join(" "))
+            }
+        }
+    }
+}
+
+impl Value {
+    pub fn truthy(&self) -> anyhow::Result<bool> {
+        use NumericLiteral::*;
+        Ok(match self {
+            Value::Numeric(Boolean(b)) => *b,
+            Value::Numeric(Float(_, n)) => *n != 0.0,
+            Value::Numeric(SysUint(n)) => *n != 0,
+            Value::Numeric(SysInt(n)) => *n != 0,
+            Value::Numeric(Uint(_, n)) | Value::Numeric(Int(_, n)) => !n.is_zero(),
+            x => bail!("{} can not be used as a condition", x),
+        })
+    }
+}
+
+pub struct Interpreter<'a> {
+    name_map: &'a NameMap,
+    pub stack: Vec<Value>,
+    output: Box<dyn Write + 'a>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(name_map: &'a NameMap) -> Self {
+        Self::with_output(name_map, Box::new(std::io::stdout()))
+    }
+
+    pub fn with_output(name_map: &'a NameMap, output: Box<dyn Write + 'a>) -> Self {
+        Self {
+            name_map,
+            stack: Vec::new(),
+            output,
+        }
+    }
+
+    pub fn write(&mut self, value: &Value) -> anyhow::Result<()> {
+        match value {
+            Value::Numeric(n) => write!(self.output, "{}", n.value_string())?,
+            Value::Str(s) => write!(self.output, "{}", s)?,
+            x => bail!("{} is not writeable", x),
+        }
+        self.output.flush()?;
+
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value)
+    }
+
+    pub fn pop(&mut self) -> anyhow::Result<Value> {
+        match self.stack.pop() {
+            Some(x) => Ok(x),
+            None => bail!("Stack underflow"),
+        }
+    }
+
+    pub fn pop_quote(&mut self) -> anyhow::Result<Rc<Closure>> {
+        match self.pop()? {
+            Value::Quote(closure) => Ok(closure),
+            x => bail!("Expected a quotation but found {}", x),
+        }
+    }
+
+    pub fn pop_list(&mut self) -> anyhow::Result<Rc<Vec<Value>>> {
+        match self.pop()? {
+            Value::List(elements) => Ok(elements),
+            x => bail!("Expected a list but found {}", x),
+        }
+    }
+
+    pub fn pop_int(&mut self) -> anyhow::Result<i64> {
+        match self.pop()? {
+            Value::Numeric(NumericLiteral::SysInt(n)) => Ok(n),
+            Value::Numeric(NumericLiteral::Int(_, n)) => match n.to_i64() {
+                Some(n) => Ok(n),
+                None => bail!("{} does not fit in 64 bits", n),
+            },
+            x => bail!("Expected an integer but found {}", x),
+        }
+    }
+
+    pub fn pop_str(&mut self) -> anyhow::Result<String> {
+        match self.pop()? {
+            Value::Str(s) => Ok(s),
+            x => bail!("Expected a string but found {}", x),
+        }
+    }
+
+    // The body to run for `name`, the first clause whose patterns match for overloaded words
+    fn body_of(&self, name: &str) -> anyhow::Result<Option<&'a [Spanned<ASTNode>]>> {
+        let name_map = self.name_map;
+
+        match name_map.get(name) {
+            Some(NameMapNode::Word { implementation, .. }) => Ok(Some(implementation)),
+            Some(NameMapNode::Overloaded { clauses, .. }) => {
+                let clause = clauses.iter().find(|clause| {
+                    clause.patterns().iter().all(|(depth, patterns)| {
+                        match self.stack.len().checked_sub(depth + 1) {
+                            Some(i) => {
+                                matches!(&self.stack[i], Value::Numeric(n) if patterns.contains(n))
+                            }
+                            None => false,
+                        }
+                    })
+                });
+
+                match clause {
+                    Some(clause) => Ok(Some(&clause.implementation)),
+                    None => bail!("No clause of {} matches the stack", name),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn call_word(&mut self, name: &str) -> anyhow::Result<()> {
+        // A word ending in a call of itself loops instead of growing the native stack
+        while let Some(body) = self.body_of(name)? {
+            match strip_self_tail_call(body, name) {
+                Some(init) => self.run(init)?,
+                None => return self.run(body),
+            }
+        }
+
+        match self.name_map.get(name) {
+            Some(NameMapNode::Word { .. }) | Some(NameMapNode::Overloaded { .. }) => {
+                unreachable!("bodies are run above")
+            }
+            Some(NameMapNode::AliasedWord(target, _)) => self.call_word(target),
+            Some(NameMapNode::StringConst(s)) => {
+                self.push(Value::Str(s.clone()));
+                Ok(())
+            }
+            Some(NameMapNode::NumericConst(n)) => {
+                self.push(Value::Numeric(n.clone()));
+                Ok(())
+            }
+            Some(NameMapNode::Builtin { builtin, .. }) => builtin.interpret(self),
+            None => bail!("Unknown word {}", name),
+        }
+    }
+
+    pub fn pop_index(&mut self) -> anyhow::Result<usize> {
+        match self.pop()? {
+            Value::Numeric(n) => match n.as_index() {
+                Some(x) => Ok(x),
+                None => bail!("{} is not a valid index", n.to_string()),
+            },
+            x => bail!("{} is not a valid index", x),
+        }
+    }
+
+    // The pointer held by the local `name`
+    fn pointer(locals: &HashMap<&str, Memory>, name: &str) -> anyhow::Result<(Memory, usize)> {
+        match locals.get(name).map(|x| x.borrow()[0].clone()) {
+            Some(Value::Ptr(memory, offset)) => Ok((memory, offset)),
+            Some(x) => bail!("{} holds {} which is not a pointer", name, x),
+            None => bail!("Unknown variable {}", name),
+        }
+    }
+
+    fn read(memory: &Memory, offset: usize) -> anyhow::Result<Value> {
+        match memory.borrow().get(offset) {
+            Some(x) => Ok(x.clone()),
+            None => bail!("Read at {} is out of bounds", offset),
+        }
+    }
+
+    fn write_at(memory: &Memory, offset: usize, value: Value) -> anyhow::Result<()> {
+        match memory.borrow_mut().get_mut(offset) {
+            Some(x) => *x = value,
+            None => bail!("Write at {} is out of bounds", offset),
+        }
+
+        Ok(())
+    }
+
+    fn run_node<'b>(
+        &mut self,
+        locals: &mut HashMap<&'b str, Memory>,
+        node: &'b ASTNode,
+    ) -> anyhow::Result<()> {
+        match node {
+            ASTNode::NumericLiteral(n) => self.push(Value::Numeric(n.clone())),
+            ASTNode::StringLiteral(s) => self.push(Value::Str(s.clone())),
+            ASTNode::Curly(body) => self.push(Value::Quote(Rc::new(Closure {
+                body: body.clone(),
+                captured: locals
+                    .iter()
+                    .map(|(name, memory)| (name.to_string(), memory.clone()))
+                    .collect(),
+            }))),
+            // The elements are the values the items leave on the stack
+            ASTNode::Square(items) => {
+                let depth = self.stack.len();
+                self.run_in(items, locals.clone())?;
+                if self.stack.len() < depth {
+                    bail!("List literal popped values from outside it");
+                }
+
+                let elements = self.stack.split_off(depth);
+                self.push(Value::List(Rc::new(elements)))
+            }
+            ASTNode::Ident(name) if locals.contains_key(name.as_str()) => {
+                let value = Self::read(&locals[name.as_str()], 0)?;
+                self.push(value)
+            }
+            ASTNode::Ident(name) => self.call_word(name)?,
+
+            // Redeclaring reuses the cell, so addresses taken before stay valid
+            ASTNode::Dec(name) | ASTNode::DecTyped(name, _) | ASTNode::DecPointer(name) => {
+                let value = self.pop()?;
+                match locals.get(name.as_str()) {
+                    Some(memory) => Self::write_at(memory, 0, value)?,
+                    None => {
+                        locals.insert(name, Rc::new(RefCell::new(vec![value])));
+                    }
+                }
+            }
+            ASTNode::Assign(name) => {
+                let value = self.pop()?;
+                match locals.get(name.as_str()) {
+                    Some(memory) => Self::write_at(memory, 0, value)?,
+                    None => bail!("Assignment to undeclared variable {}", name),
+                }
+            }
+
+            ASTNode::DecArraySized(name, _) | ASTNode::DecArrayVariable(name) => {
+                let size = match node {
+                    ASTNode::DecArraySized(_, size) => size.as_index().unwrap_or(0),
+                    _ => self.pop_index()?,
+                };
+                let memory = vec![Value::Numeric(NumericLiteral::SysInt(0)); size];
+                let value = Value::Ptr(Rc::new(RefCell::new(memory)), 0);
+
+                locals.insert(name, Rc::new(RefCell::new(vec![value])));
+            }
+            ASTNode::Address(name) => match locals.get(name.as_str()) {
+                Some(memory) => self.push(Value::Ptr(memory.clone(), 0)),
+                None => bail!("Unknown variable {}", name),
+            },
+            ASTNode::ReadAddress(name) => {
+                let (memory, offset) = Self::pointer(locals, name)?;
+                self.push(Self::read(&memory, offset)?)
+            }
+            ASTNode::ReadIndex(name) => {
+                let (memory, offset) = Self::pointer(locals, name)?;
+                let index = self.pop_index()?;
+                self.push(Self::read(&memory, offset + index)?)
+            }
+            ASTNode::PointerAssign(name) => {
+                let (memory, offset) = Self::pointer(locals, name)?;
+                let value = self.pop()?;
+                Self::write_at(&memory, offset, value)?
+            }
+            ASTNode::IndexAssign(name) => {
+                let (memory, offset) = Self::pointer(locals, name)?;
+                let index = self.pop_index()?;
+                let value = self.pop()?;
+                Self::write_at(&memory, offset + index, value)?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn run(&mut self, body: &[Spanned<ASTNode>]) -> anyhow::Result<()> {
+        self.run_in(body, HashMap::new())
+    }
+
+    pub fn call_closure(&mut self, closure: &Closure) -> anyhow::Result<()> {
+        let locals = closure
+            .captured
+            .iter()
+            .map(|(name, memory)| (name.as_str(), memory.clone()))
+            .collect();
+
+        self.run_in(&closure.body, locals)
+    }
+
+    // Locals are visible to the rest of the body they are declared in and to its quotations
+    fn run_in<'b>(
+        &mut self,
+        body: &'b [Spanned<ASTNode>],
+        mut locals: HashMap<&'b str, Memory>,
+    ) -> anyhow::Result<()> {
+        for Spanned { node, span } in body {
+            locate(self.run_node(&mut locals, node), *span)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn interpret(name_map: &NameMap) -> anyhow::Result<Vec<Value>> {
+    let mut interpreter = Interpreter::new(name_map);
+
+    interpreter.call_word("main")?;
+
+    Ok(interpreter.stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{interpret, Interpreter};
+    use crate::namemap::NameMap;
+
+    fn load(program: &str) -> NameMap {
+        let program = crate::tokenizer::tokenizer(program.to_string()).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        crate::namemap::extract_name_map(program).unwrap()
+    }
+
+    // The stack left by main and everything it wrote
+    fn run_with_output(program: &str) -> (Vec<String>, String) {
+        let program = load(program);
+
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::with_output(&program, Box::new(&mut output));
+        interpreter.call_word("main").unwrap();
+        let stack = std::mem::take(&mut interpreter.stack);
+        drop(interpreter);
+
+        (
+            stack.iter().map(|x| x.to_string()).collect(),
+            String::from_utf8(output).unwrap(),
+        )
+    }
+
+    fn run(program: &str) -> Vec<String> {
+        run_with_output(program).0
+    }
+
+    fn fails(program: &str) -> bool {
+        interpret(&load(program)).is_err()
+    }
+
+    #[test]
+    fn it_calls_words() {
+        assert_eq!(
+            run("@two 2 @name \"sbl\" @alias two @main { 1 alias name }"),
+            vec!["1i", "2i", "\"sbl\""]
+        );
+    }
+
+    #[test]
+    fn it_runs_quotations() {
+        assert_eq!(run("@main { { 1 { 2 } @ } @ }"), vec!["1i", "2i"]);
+    }
+
+    #[test]
+    fn it_runs_builtins() {
+        let (stack, output) =
+            run_with_output("@main { 1 2 swap dup 2 pick - \"=\" . . 7u8 3u8 / . 1 1 = . }");
+
+        assert_eq!(stack, vec!["2i", "1i"]);
+        assert_eq!(output, "=-121");
+    }
+
+    #[test]
+    fn it_uses_locals() {
+        assert_eq!(
+            run("@sub { ::b ::a a b - } @main { 5 3 sub ::x 1 :x x x 10 sub }"),
+            vec!["1i", "-9i"]
+        );
+    }
+
+    #[test]
+    fn it_uses_pointers() {
+        let stack = run("@main {
+            :#a[3] 3 :#b[] 7 ::x
+            1 0 $:a[] 2 1 $:a[] 0 $a[] 1 $a[] + 2 $:b[]
+            #x :#p 8 $:#p $p x 2 $b[]
+        }");
+
+        assert_eq!(stack, vec!["8i", "8i", "3i"]);
+    }
+
+    #[test]
+    fn it_dispatches_clauses() {
+        let stack = run("?fib [-Int@(1 | 2) +Int] @fib { drop 1 }
+                         ?fib [-Int +Int] @fib { dup 1 - fib swap 2 - fib + }
+                         @main { 10 fib 1 fib }");
+
+        assert_eq!(stack, vec!["55i", "1i"]);
+    }
+
+    #[test]
+    fn it_runs_the_prelude() {
+        assert_eq!(
+            run("@main { 1 2 over 3 rot nip 5 6 tuck 2dup }"),
+            vec!["1i", "1i", "2i", "6i", "5i", "6i", "5i", "6i"]
+        );
+
+        let stack = run("@main {
+            -5 abs 7 3 min 7 3 max 7 3 mod
+            0 3 { 1 + } times 0 -2 { 1 + } times
+            0 { dup 5 < } { 2 + } while
+        }");
+        assert_eq!(stack, vec!["5i", "3i", "7i", "1i", "3i", "0i", "6i"]);
+
+        // Prelude words keep their own helpers when the program redefines one
+        assert_eq!(
+            run("?2dup [-Int -Int +Int] @2dup { drop } @main { 7 3 min 7 3 2dup }"),
+            vec!["3i", "7i"]
+        );
+    }
+
+    #[test]
+    fn it_slices_strings() {
+        assert_eq!(
+            run("@main { \"sb\" \"lc\" concat dup len swap 1 2 substr \"abc\" 1 3 strslice }"),
+            vec!["4i", "\"bl\"", "\"bc\""]
+        );
+        assert!(fails("@main { \"ab\" 1 5 substr }"));
+    }
+
+    #[test]
+    fn it_iterates_code_points() {
+        let stack = run("@main {
+            \"aλ😀\" ::s s len s charcount s 1 nextchar
+            '😀' charstr s 3 4 substr = 'λ' 955u32 =
+            0u32 ::sum s { sum + :sum } eachchar sum
+        }");
+
+        assert_eq!(
+            stack,
+            vec!["7i", "3i", "955u32", "3i", "1b", "1b", "129564u32"]
+        );
+
+        for program in [
+            "@main { \"λ\" 1 nextchar }",
+            "@main { \"λ\" 2 nextchar }",
+            "@main { \"λ\" 0 1 substr }",
+            "@main { 0xd800u32 charstr }",
+        ]
+        .iter()
+        {
+            assert!(fails(program), "{}", program);
+        }
+    }
+
+    #[test]
+    fn it_loops() {
+        assert_eq!(
+            run("@main { 0 3 { 1 + } times 0 0 5 { + } for 1 { dup 100 < } { 2 * } while }"),
+            vec!["3i", "10i", "128i"]
+        );
+    }
+
+    #[test]
+    fn it_builds_lists() {
+        let stack = run("@main {
+            2 ::x [1 x 0x10 [\"a\"]] ::l
+            l l size l 2 at l 3 at 0 at
+            0 ::sum l { drop sum 1 + :sum } foreach sum
+        }");
+
+        assert_eq!(
+            stack,
+            vec!["[1i 2i 16i [\"a\"]]", "4i", "16i", "\"a\"", "4i"]
+        );
+        assert!(fails("@main { 1 [drop] }"));
+        assert!(fails("@main { [1 2] 2 at }"));
+    }
+
+    #[test]
+    fn it_wraps_wide_integers() {
+        let stack = run("@main {
+            0xffff_ffff_ffff_ffff_ffffu128 ::x x x *
+            170141183460469231731687303715884105727i128 ::m m 1i128 +
+        }");
+
+        assert_eq!(
+            stack,
+            vec![
+                "340282366920936045611735378173418799105u128",
+                "-170141183460469231731687303715884105728i128"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_runs_tail_calls_in_constant_space() {
+        let stack = run("?down [-Int@0 +Int] @down { }
+                         ?down [-Int +Int] @down { 1 - down }
+                         @main { 100000 down }");
+
+        assert_eq!(stack, vec!["0i"]);
+    }
+
+    #[test]
+    fn it_captures_locals() {
+        let stack = run("@adder { ::n { n + } }
+                         @main {
+                             5 adder ::add5 1 add5 @
+                             0 ::count 3 { count 1 + :count } times count
+                         }");
+        assert_eq!(stack, vec!["6i", "3i"]);
+
+        let stack = run("@main {
+            :#a[4] 1 0 $:a[] 2 1 $:a[] 3 2 $:a[] 4 3 $:a[]
+            a 4 { 10 * } map
+            a 4 { 25 > } filter ::k :#b
+            k 0 $b[] 1 $b[]
+            0 ::sum a 4 { sum + :sum } each sum
+        }");
+        assert_eq!(stack, vec!["2i", "30i", "40i", "100i"]);
+    }
+
+    #[test]
+    fn it_branches() {
+        assert_eq!(
+            run("@main { 1b { 1 } if { 2 } else 0b { 3 } if { 4 } else }"),
+            vec!["1i", "1b", "4i", "0b"]
+        );
+    }
+}
@@ -1,13 +1,86 @@
 mod ast;
+mod builtins;
+mod callgraph;
 mod compiler;
+mod constfold;
+mod diagnostic;
+mod error;
+mod interpreter;
+mod modules;
 mod namemap;
 mod numeric_litteral;
 mod repl;
+mod sblc;
+mod span;
 mod tokenizer;
+mod typecheck;
 // mod colidescope;
 
+fn run(args: &[String]) -> anyhow::Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => anyhow::bail!("Usage: run <input.sbl>"),
+    };
+
+    let source = std::fs::read_to_string(path)?;
+    let mut sources = diagnostic::Sources::new(path, &source);
+
+    let result = (|| {
+        let program = tokenizer::tokenizer(source.clone())?;
+        let program = ast::build_tree(program)?;
+        let program = modules::resolve_imports(program, &mut sources)?;
+        let program = namemap::extract_name_map(program)?;
+        if let Some(err) = namemap::validate(&program).into_iter().next() {
+            return Err(err.into());
+        }
+
+        for warning in namemap::warnings(&program) {
+            eprintln!("{}\n", sources.render_warning(&warning));
+        }
+
+        let errors = typecheck::check_name_map(&program);
+        if !errors.is_empty() {
+            for error in errors.iter() {
+                eprintln!("{}\n", sources.render(&error.diagnostic()));
+            }
+            anyhow::bail!("{} type error(s) in {}", errors.len(), path)
+        }
+
+        interpreter::interpret(&program)
+    })();
+    let stack = result.map_err(|err| anyhow::anyhow!(sources.render_error(&err)))?;
+
+    for value in stack {
+        println!("{}", value);
+    }
+
+    Ok(())
+}
+
 fn main() {
-    repl::repl();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.split_first() {
+        Some((mode, args)) if mode == "sblc" => {
+            if let Err(err) = sblc::sblc(args) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some((mode, args)) if mode == "run" => {
+            if let Err(err) = run(args) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(_) => {
+            eprintln!(
+                "Usage: stack_base_langauge [sblc <input.sbl> [-o <output>] | run <input.sbl>]"
+            );
+            std::process::exit(1);
+        }
+        None => repl::repl(),
+    }
 }
 
 // fn main() -> anyhow::Result<()> {
@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    ast::{build_tree, ASTNode, TopLevelNode},
+    diagnostic::Sources,
+    error::SblError,
+    span::Spanned,
+    tokenizer::tokenizer_in,
+};
+
+// An error located in one of the loaded files, rendered against that file
+#[derive(Debug)]
+pub struct ModuleError {
+    pub path: String,
+    pub source: String,
+    pub error: Box<SblError>,
+}
+
+impl ModuleError {
+    pub fn render(&self) -> String {
+        self.error.diagnostic().render(&self.source, &self.path)
+    }
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path, self.error.diagnostic())
+    }
+}
+
+impl std::error::Error for ModuleError {}
+
+// Renames the words of a module to `namespace/word`, locals and other words are left as is
+fn qualify_body(
+    body: &mut [Spanned<ASTNode>],
+    names: &HashSet<String>,
+    namespace: &str,
+    locals: &mut HashSet<String>,
+) {
+    for Spanned { node, .. } in body.iter_mut() {
+        match node {
+            ASTNode::Ident(s) if names.contains(s.as_str()) && !locals.contains(s.as_str()) => {
+                *s = format!("{}/{}", namespace, s)
+            }
+            ASTNode::Curly(body) | ASTNode::Square(body) => {
+                qualify_body(body, names, namespace, &mut locals.clone())
+            }
+            ASTNode::Dec(s)
+            | ASTNode::DecTyped(s, _)
+            | ASTNode::DecPointer(s)
+            | ASTNode::DecArraySized(s, _)
+            | ASTNode::DecArrayVariable(s) => {
+                locals.insert(s.clone());
+            }
+            _ => (),
+        }
+    }
+}
+
+pub fn qualify(nodes: &mut [Spanned<TopLevelNode>], namespace: &str) {
+    let names: HashSet<String> = nodes
+        .iter()
+        .filter_map(|x| match &x.node {
+            TopLevelNode::WordDeclare(name, _) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+
+    for Spanned { node, .. } in nodes.iter_mut() {
+        match node {
+            TopLevelNode::WordDeclare(name, implementation) => {
+                *name = format!("{}/{}", namespace, name);
+                qualify_body(
+                    std::slice::from_mut(implementation),
+                    &names,
+                    namespace,
+                    &mut HashSet::new(),
+                );
+            }
+            TopLevelNode::Typing(name, _) if names.contains(name.as_str()) => {
+                *name = format!("{}/{}", namespace, name)
+            }
+            _ => (),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Loader {
+    // Files currently being loaded, an import of one of them is a cycle
+    loading: Vec<PathBuf>,
+    loaded: HashSet<PathBuf>,
+    out: Vec<Spanned<TopLevelNode>>,
+    sources: Sources,
+}
+
+impl Loader {
+    fn load(
+        &mut self,
+        tree: Vec<Spanned<TopLevelNode>>,
+        path: &Path,
+        source: &str,
+        namespace: Option<&str>,
+    ) -> Result<(), ModuleError> {
+        let located = |error| ModuleError {
+            path: path.to_string_lossy().to_string(),
+            source: source.to_string(),
+            error: Box::new(error),
+        };
+
+        self.loading
+            .push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+
+        let mut own = Vec::new();
+        for node in tree {
+            let (import, span) = match node {
+                Spanned {
+                    node: TopLevelNode::Use(import),
+                    span,
+                } => (import, span),
+                _ => {
+                    own.push(node);
+                    continue;
+                }
+            };
+
+            let mut import_path = path.parent().unwrap_or_else(|| Path::new("")).join(&import);
+            if import_path.extension().is_none() {
+                import_path.set_extension("sbl");
+            }
+
+            let canonical = import_path
+                .canonicalize()
+                .map_err(|_| located(SblError::ImportNotFound(import.clone(), span)))?;
+            if self.loading.contains(&canonical) {
+                return Err(located(SblError::CyclicImport(import, span)));
+            }
+            if !self.loaded.insert(canonical) {
+                continue;
+            }
+
+            let import_source = std::fs::read_to_string(&import_path)
+                .map_err(|_| located(SblError::ImportNotFound(import.clone(), span)))?;
+            let import_located = |error| ModuleError {
+                path: import_path.to_string_lossy().to_string(),
+                source: import_source.clone(),
+                error: Box::new(error),
+            };
+
+            let file = self
+                .sources
+                .add(&import_path.to_string_lossy(), &import_source);
+            let tokens = tokenizer_in(import_source.clone(), file).map_err(import_located)?;
+            let import_tree = build_tree(tokens).map_err(import_located)?;
+
+            let namespace = import_path
+                .file_stem()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            self.load(import_tree, &import_path, &import_source, Some(&namespace))?;
+        }
+
+        if let Some(namespace) = namespace {
+            qualify(&mut own, namespace);
+        }
+        self.out.extend(own);
+        self.loading.pop();
+
+        Ok(())
+    }
+}
+
+// Replaces every `use` with the declarations of the imported file, before the importer's own
+// The importer is the first of the sources, every imported file is added to them and the spans
+// of its declarations carry its index
+pub fn resolve_imports(
+    tree: Vec<Spanned<TopLevelNode>>,
+    sources: &mut Sources,
+) -> Result<Vec<Spanned<TopLevelNode>>, ModuleError> {
+    let (path, source) = sources.get(0);
+    let (path, source) = (PathBuf::from(path), source.to_string());
+
+    let mut loader = Loader {
+        sources: std::mem::take(sources),
+        ..Loader::default()
+    };
+    let result = loader.load(tree, &path, &source, None);
+    *sources = loader.sources;
+    result?;
+
+    Ok(loader.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::resolve_imports;
+    use crate::diagnostic::Sources;
+    use crate::error::SblError;
+
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sbl_modules_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+
+        for (path, source) in files {
+            std::fs::write(dir.join(path), source).unwrap();
+        }
+
+        dir
+    }
+
+    fn load_with_sources(
+        path: &Path,
+    ) -> Result<(crate::namemap::NameMap, Sources), super::ModuleError> {
+        let source = std::fs::read_to_string(path).unwrap();
+        let tree =
+            crate::ast::build_tree(crate::tokenizer::tokenizer(source.clone()).unwrap()).unwrap();
+        let mut sources = Sources::new(&path.to_string_lossy(), &source);
+        let tree = resolve_imports(tree, &mut sources)?;
+
+        Ok((crate::namemap::extract_name_map(tree).unwrap(), sources))
+    }
+
+    fn load(path: &Path) -> Result<crate::namemap::NameMap, super::ModuleError> {
+        Ok(load_with_sources(path)?.0)
+    }
+
+    #[test]
+    fn it_namespaces_imports() {
+        let dir = write_files(
+            "namespaces",
+            &[
+                (
+                    "main.sbl",
+                    "use \"lib/math\" use \"lib/util.sbl\" @main { 3 math/square }",
+                ),
+                (
+                    "lib/math.sbl",
+                    "use \"util\" ?square [-Int +Int] @square { ::x x x util/mul } @two 2",
+                ),
+                (
+                    "lib/util.sbl",
+                    "@mul { * } @times mul @listed { [1 ::mul] 2 3 mul }",
+                ),
+            ],
+        );
+
+        let map = load(&dir.join("main.sbl")).unwrap();
+
+        assert!(map.contains_key("math/square"));
+        assert!(map.contains_key("util/times"));
+        assert!(crate::namemap::validate(&map).is_empty());
+        assert!(crate::typecheck::check_name_map(&map).is_empty());
+        assert!(matches!(
+            &map["util/times"],
+            crate::namemap::NameMapNode::AliasedWord(x, _) if x == "util/mul"
+        ));
+    }
+
+    #[test]
+    fn it_renders_errors_in_their_file() {
+        let dir = write_files(
+            "render",
+            &[
+                ("main.sbl", "use \"lib/bad\"\n@main { bad/oops }"),
+                ("lib/bad.sbl", "@oops {\n  nope }"),
+            ],
+        );
+
+        let (map, sources) = load_with_sources(&dir.join("main.sbl")).unwrap();
+        let errors = crate::namemap::validate(&map);
+        assert_eq!(errors.len(), 1);
+
+        let rendered = sources.render(&errors[0].diagnostic());
+        assert!(rendered.contains("bad.sbl:2:3"), "{}", rendered);
+        assert!(rendered.contains("2 |   nope }"), "{}", rendered);
+    }
+
+    #[test]
+    fn it_detects_cycles() {
+        let dir = write_files(
+            "cycles",
+            &[
+                ("main.sbl", "use \"lib/a\" @main { }"),
+                ("lib/a.sbl", "use \"b\" @a 1"),
+                ("lib/b.sbl", "use \"a\" @b 2"),
+            ],
+        );
+
+        let err = load(&dir.join("main.sbl")).unwrap_err();
+        assert!(matches!(*err.error, SblError::CyclicImport(..)));
+        assert!(err.path.ends_with("b.sbl"));
+
+        let dir = write_files("missing", &[("main.sbl", "use \"nope\" @main { }")]);
+        let err = load(&dir.join("main.sbl")).unwrap_err();
+        assert!(matches!(*err.error, SblError::ImportNotFound(..)));
+    }
+}
@@ -1,65 +1,380 @@
-use std::collections::HashMap;
-
-use anyhow::bail;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
-    ast::{ASTNode, TopLevelNode},
+    ast::{build_tree, ASTNode, TopLevelNode, TypingASTNode},
+    builtins::{self, Builtin},
+    diagnostic::Diagnostic,
+    error::SblError,
+    modules::qualify,
     numeric_litteral::NumericLiteral,
+    span::{Span, Spanned},
+    tokenizer::tokenizer,
 };
 
-#[derive(Debug)]
+// Words written in SBL itself, declared before those of every program
+const PRELUDE: &str = include_str!("prelude.sbl");
+
+fn prelude() -> Vec<Spanned<TopLevelNode>> {
+    let tokens = tokenizer(PRELUDE.to_string()).expect("invalid prelude");
+    let mut tree = build_tree(tokens).expect("invalid prelude");
+    qualify(&mut tree, "prelude");
+    tree
+}
+
+#[derive(Debug, Clone)]
 pub enum NameMapNode {
     Word {
-        implementation: Vec<ASTNode>,
-        depends_on: Vec<String>,
+        implementation: Vec<Spanned<ASTNode>>,
+        depends_on: Vec<Spanned<String>>,
+        signature: Option<Vec<TypingASTNode>>,
+        span: Span,
+    },
+    // Clauses are tried in order, the first whose patterns match the stack runs
+    Overloaded {
+        clauses: Vec<Clause>,
+        depends_on: Vec<Spanned<String>>,
+        span: Span,
+    },
+    AliasedWord(String, Span),
+    Builtin {
+        builtin: Builtin,
+        signature: Vec<TypingASTNode>,
     },
-    AliasedWord(String),
 
     StringConst(String),
     NumericConst(NumericLiteral),
 }
 pub type NameMap = HashMap<String, NameMapNode>;
 
-pub fn extract_name_map(base: Vec<TopLevelNode>) -> anyhow::Result<NameMap> {
+#[derive(Debug, Clone)]
+pub struct Clause {
+    pub implementation: Vec<Spanned<ASTNode>>,
+    pub signature: Vec<TypingASTNode>,
+    pub span: Span,
+}
+
+impl Clause {
+    // The literals each matched value must be one of, by depth from the top of the stack
+    pub fn patterns(&self) -> Vec<(usize, &[NumericLiteral])> {
+        // -* takes no particular value
+        let is_value = |x: &&TypingASTNode| match x {
+            TypingASTNode::Pop(c) => !(c.poly && c.variable.is_none()),
+            TypingASTNode::Match(..) => true,
+            _ => false,
+        };
+        let pops = self.signature.iter().filter(is_value).count();
+
+        self.signature
+            .iter()
+            .filter(is_value)
+            .enumerate()
+            .filter_map(|(i, x)| match x {
+                TypingASTNode::Match(_, patterns) => Some((pops - 1 - i, patterns.as_slice())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn has_patterns(signature: &[TypingASTNode]) -> bool {
+    signature
+        .iter()
+        .any(|x| matches!(x, TypingASTNode::Match(..)))
+}
+
+// Only clauses with patterns can be followed by more, anything after a catch-all is unreachable
+fn accepts_clause(node: &NameMapNode) -> bool {
+    match node {
+        NameMapNode::Word {
+            signature: Some(signature),
+            ..
+        } => has_patterns(signature),
+        NameMapNode::Overloaded { clauses, .. } => {
+            clauses.iter().all(|x| has_patterns(&x.signature))
+        }
+        _ => false,
+    }
+}
+
+// Only called on words whose signature is known to be there
+fn into_clause(node: NameMapNode) -> (Clause, Vec<Spanned<String>>) {
+    match node {
+        NameMapNode::Word {
+            implementation,
+            depends_on,
+            signature: Some(signature),
+            span,
+        } => (
+            Clause {
+                implementation,
+                signature,
+                span,
+            },
+            depends_on,
+        ),
+        _ => unreachable!("only typed words are clauses"),
+    }
+}
+
+// Adds a clause to a word whose earlier clauses all have patterns
+fn add_clause(previous: NameMapNode, value: NameMapNode) -> NameMapNode {
+    let (mut clauses, mut depends_on, span) = match previous {
+        NameMapNode::Overloaded {
+            clauses,
+            depends_on,
+            span,
+        } => (clauses, depends_on, span),
+        word => {
+            let (clause, depends_on) = into_clause(word);
+            let span = clause.span;
+            (vec![clause], depends_on, span)
+        }
+    };
+
+    let (clause, next_depends_on) = into_clause(value);
+    clauses.push(clause);
+    depends_on.extend(next_depends_on);
+
+    NameMapNode::Overloaded {
+        clauses,
+        depends_on,
+        span,
+    }
+}
+
+// Names bound or used by the variable and pointer forms are locals of their body, not words
+fn walk_dependencies<'a>(
+    body: &'a [Spanned<ASTNode>],
+    locals: &mut HashSet<&'a str>,
+    out: &mut Vec<Spanned<String>>,
+) {
+    for Spanned { node, span } in body {
+        match node {
+            ASTNode::Ident(s) => {
+                if !locals.contains(s.as_str()) {
+                    out.push(Spanned::new(s.clone(), *span))
+                }
+            }
+            // Quotations capture the locals declared before them, locals declared inside a
+            // quotation or a list literal end with it
+            ASTNode::Curly(body) | ASTNode::Square(body) => {
+                walk_dependencies(body, &mut locals.clone(), out)
+            }
+
+            ASTNode::Dec(s)
+            | ASTNode::DecTyped(s, _)
+            | ASTNode::DecPointer(s)
+            | ASTNode::DecArraySized(s, _)
+            | ASTNode::DecArrayVariable(s) => {
+                locals.insert(s.as_str());
+            }
+            ASTNode::Assign(_)
+            | ASTNode::PointerAssign(_)
+            | ASTNode::IndexAssign(_)
+            | ASTNode::ReadIndex(_)
+            | ASTNode::Address(_)
+            | ASTNode::ReadAddress(_)
+            | ASTNode::NumericLiteral(_)
+            | ASTNode::StringLiteral(_) => (),
+        }
+    }
+}
+
+// Every word referenced by the body, including the ones in nested quotations
+fn dependencies(body: &[Spanned<ASTNode>]) -> Vec<Spanned<String>> {
+    let mut out = Vec::new();
+    walk_dependencies(body, &mut HashSet::new(), &mut out);
+
+    out
+}
+
+// Keeps the first definition of a name when it is redefined
+pub fn extract_name_map_recovering(base: Vec<Spanned<TopLevelNode>>) -> (NameMap, Vec<SblError>) {
     let mut map = NameMap::new();
+    let mut errors = Vec::new();
+    builtins::register(&mut map);
+    // ?word [...] applies to the next @word of the same name
+    let mut typings: HashMap<String, (Vec<TypingASTNode>, Span)> = HashMap::new();
+
+    let prelude = prelude();
+    let aliases: Vec<(String, Span)> = prelude
+        .iter()
+        .filter_map(|x| match &x.node {
+            TopLevelNode::WordDeclare(name, _) => Some((name.clone(), x.span)),
+            _ => None,
+        })
+        .collect();
 
-    for node in base {
+    for Spanned { node, span } in prelude.into_iter().chain(base) {
         match node {
-            TopLevelNode::WordDeclare(ident, implementation) => match map.insert(
-                ident,
-                match implementation {
-                    ASTNode::Curly(a) => {
-                        let mut depends_on = Vec::new();
-
-                        for value in a.iter() {
-                            if let ASTNode::Ident(s) = value {
-                                depends_on.push(s.clone())
-                            }
-                        }
-                        NameMapNode::Word {
-                            implementation: a,
-                            depends_on,
-                        }
+            TopLevelNode::WordDeclare(ident, implementation) => {
+                let signature = typings.remove(&ident).map(|(types, _)| types);
+
+                let value = match implementation.node {
+                    ASTNode::Curly(a) => NameMapNode::Word {
+                        depends_on: dependencies(&a),
+                        implementation: a,
+                        signature,
+                        span,
+                    },
+                    _ if signature.is_some() => {
+                        errors.push(SblError::UntypeableWord(ident, span));
+                        continue;
                     }
-                    ASTNode::Ident(a) => NameMapNode::AliasedWord(a),
+                    ASTNode::Ident(a) => NameMapNode::AliasedWord(a, span),
                     ASTNode::NumericLiteral(a) => NameMapNode::NumericConst(a),
                     ASTNode::StringLiteral(s) => NameMapNode::StringConst(s),
-                    _ => bail!("Bad word declaration value"),
-                },
-            ) {
-                Some(x) => todo!(),
-                None => (),
+                    _ => {
+                        errors.push(SblError::BadDeclaration(implementation.span));
+                        continue;
+                    }
+                };
+
+                match map.get(&ident) {
+                    None => {
+                        map.insert(ident, value);
+                    }
+                    Some(previous) if accepts_clause(previous) => match value {
+                        NameMapNode::Word {
+                            signature: Some(_), ..
+                        } => {
+                            let previous = map.remove(&ident).unwrap();
+                            map.insert(ident, add_clause(previous, value));
+                        }
+                        NameMapNode::Word { .. } => {
+                            errors.push(SblError::UntypedClause(ident, span));
+                        }
+                        _ => errors.push(SblError::Redefinition(ident, span)),
+                    },
+                    Some(_) => errors.push(SblError::Redefinition(ident, span)),
+                }
+            }
+            TopLevelNode::Use(path) => errors.push(SblError::UnresolvedImport(path, span)),
+            TopLevelNode::Typing(ident, types) => {
+                if typings.insert(ident.clone(), (types, span)).is_some() {
+                    errors.push(SblError::DoubleTyping(ident, span));
+                }
+            }
+        }
+    }
+
+    let mut undeclared: Vec<_> = typings.into_iter().collect();
+    undeclared.sort_by_key(|(_, (_, span))| span.start);
+    for (ident, (_, span)) in undeclared {
+        errors.push(SblError::UndeclaredTyping(ident, span));
+    }
+
+    // The plain names of prelude words the program leaves undefined
+    for (name, span) in aliases {
+        let short = name.trim_start_matches("prelude/").to_string();
+        map.entry(short)
+            .or_insert(NameMapNode::AliasedWord(name, span));
+    }
+
+    // A lone clause with patterns still needs its dispatch
+    let lone: Vec<String> = map
+        .iter()
+        .filter(|(_, x)| accepts_clause(x) && matches!(x, NameMapNode::Word { .. }))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in lone {
+        let (clause, depends_on) = into_clause(map.remove(&name).unwrap());
+        let span = clause.span;
+
+        map.insert(
+            name,
+            NameMapNode::Overloaded {
+                clauses: vec![clause],
+                depends_on,
+                span,
             },
-            TopLevelNode::Typing(_, _) => todo!(),
+        );
+    }
+
+    (map, errors)
+}
+
+pub fn extract_name_map(base: Vec<Spanned<TopLevelNode>>) -> Result<NameMap, SblError> {
+    let (map, errors) = extract_name_map_recovering(base);
+
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(map),
+    }
+}
+
+// Follows the alias chain starting at `name` and tells if it comes back to it
+fn is_cyclic_alias(map: &NameMap, name: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut current = name;
+
+    while let Some(NameMapNode::AliasedWord(target, _)) = map.get(current) {
+        if target == name {
+            return true;
+        }
+        if !seen.insert(target.as_str()) {
+            return false;
         }
+        current = target;
     }
 
-    Ok(map)
+    false
+}
+
+// Calls to undefined words and aliases which never reach a word
+pub fn validate(map: &NameMap) -> Vec<SblError> {
+    let mut errors = Vec::new();
+
+    for (name, node) in map.iter() {
+        match node {
+            NameMapNode::Word { depends_on, .. } | NameMapNode::Overloaded { depends_on, .. } => {
+                for word in depends_on {
+                    if !map.contains_key(&word.node) {
+                        errors.push(SblError::UndefinedWord(word.node.clone(), word.span));
+                    }
+                }
+            }
+            NameMapNode::AliasedWord(target, span) if !map.contains_key(target) => errors.push(
+                SblError::UndefinedAliasTarget(name.clone(), target.clone(), *span),
+            ),
+            NameMapNode::AliasedWord(_, span) if is_cyclic_alias(map, name) => {
+                errors.push(SblError::CyclicAlias(name.clone(), *span))
+            }
+            _ => (),
+        }
+    }
+    errors.sort_by_key(|x| x.span().start);
+
+    errors
+}
+
+pub fn warnings(map: &NameMap) -> Vec<Diagnostic> {
+    let mut warnings = Vec::new();
+
+    for (name, node) in map.iter() {
+        if let NameMapNode::Overloaded { clauses, span, .. } = node {
+            if clauses.iter().all(|x| has_patterns(&x.signature)) {
+                warnings.push(Diagnostic::new(
+                    *span,
+                    format!(
+                        "The patterns of {} do not cover every case, add a clause without patterns",
+                        name
+                    ),
+                ));
+            }
+        }
+    }
+    warnings.sort_by_key(|x| x.span.start);
+
+    warnings
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::namemap::extract_name_map;
+    use crate::error::SblError;
+    use crate::namemap::{
+        extract_name_map, extract_name_map_recovering, validate, warnings, NameMapNode,
+    };
 
     #[test]
     fn exp() {
@@ -70,4 +385,144 @@ mod tests {
 
         println!("{:?}", program);
     }
+
+    #[test]
+    fn it_collects_clauses() {
+        let program = "?fib [-Int@(1 | 2) +Int] @fib { drop 1 }
+                       ?fib [-Int +Int] @fib { dup 1 - fib swap 2 - fib + }"
+            .to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let program = extract_name_map(program).unwrap();
+
+        match &program["fib"] {
+            NameMapNode::Overloaded { clauses, .. } => {
+                assert_eq!(clauses.len(), 2);
+                assert_eq!(clauses[0].patterns().len(), 1);
+                assert!(clauses[1].patterns().is_empty());
+            }
+            x => panic!("Expected clauses, got {:?}", x),
+        }
+        assert_eq!(warnings(&program).len(), 0);
+
+        let program = "?half [-Int@0 +Int] @half { } ?half [-Int@1 +Int] @half { }".to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let program = extract_name_map(program).unwrap();
+        assert_eq!(warnings(&program).len(), 1);
+    }
+
+    #[test]
+    fn it_attaches_typings_forward() {
+        let program = "?a [+Int] @a { 1 } @b { 2 } ?b [+Int] ?c [+Int]".to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+
+        let (map, errors) = extract_name_map_recovering(program);
+        assert!(matches!(
+            &map["a"],
+            NameMapNode::Word {
+                signature: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            &map["b"],
+            NameMapNode::Word {
+                signature: None,
+                ..
+            }
+        ));
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], SblError::UndeclaredTyping(x, _) if x == "b"));
+        assert!(matches!(&errors[1], SblError::UndeclaredTyping(x, _) if x == "c"));
+    }
+
+    #[test]
+    fn it_validates_names() {
+        let program = "@main { ::x x nope { also_nope } }
+                       @a b @b c @c a @d missing @e a"
+            .to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let program = extract_name_map(program).unwrap();
+
+        let errors = validate(&program);
+        assert_eq!(errors.len(), 6);
+        assert!(matches!(&errors[0], SblError::UndefinedWord(x, _) if x == "nope"));
+        assert!(matches!(&errors[1], SblError::UndefinedWord(x, _) if x == "also_nope"));
+        assert!(matches!(&errors[2], SblError::CyclicAlias(x, _) if x == "a"));
+        assert!(matches!(&errors[5], SblError::UndefinedAliasTarget(x, _, _) if x == "d"));
+    }
+
+    #[test]
+    fn it_collects_nested_dependencies() {
+        let program =
+            "@main { ::x x :#p[2] $p 1b { \"a\" . x { inner } @ } if [ listed ] }".to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let program = extract_name_map(program).unwrap();
+
+        let depends_on = match &program["main"] {
+            NameMapNode::Word { depends_on, .. } => depends_on
+                .iter()
+                .map(|x| x.node.as_str())
+                .collect::<Vec<_>>(),
+            x => panic!("Expected word, got {:?}", x),
+        };
+        assert_eq!(depends_on, vec![".", "inner", "@", "if", "listed"]);
+
+        let program = "@main { [1 ::item] item }".to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let (map, _) = extract_name_map_recovering(program);
+        assert!(matches!(
+            &map["main"],
+            NameMapNode::Word { depends_on, .. } if depends_on[0].node == "item"
+        ));
+    }
+
+    #[test]
+    fn it_reports_every_redefinition() {
+        let program = "@a 1 @a 2 @dup { } @b { } @b 3".to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+
+        let (map, errors) = extract_name_map_recovering(program);
+        assert_eq!(errors.len(), 3);
+        assert!(errors
+            .iter()
+            .all(|x| matches!(x, SblError::Redefinition(..))));
+        assert_eq!(errors[0].span().column, 6);
+        assert!(matches!(map["a"], NameMapNode::NumericConst(_)));
+    }
+
+    #[test]
+    fn it_replaces_prelude_words() {
+        let program = "?over [-Int -Int +Int] @over { drop } @main { 1 2 over 3 min }";
+        let program = crate::tokenizer::tokenizer(program.to_string()).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let (map, errors) = extract_name_map_recovering(program);
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(validate(&map).is_empty());
+        assert!(matches!(
+            &map["over"],
+            NameMapNode::Word { implementation, .. } if implementation.len() == 1
+        ));
+        assert!(matches!(&map["min"], NameMapNode::AliasedWord(x, _) if x == "prelude/min"));
+        assert!(matches!(&map["prelude/over"], NameMapNode::Word { .. }));
+    }
+
+    #[test]
+    fn it_rejects_unreachable_clauses() {
+        let program = "?a [-Int +Int] @a { } ?a [-Int@1 +Int] @a { }".to_string();
+        let program = crate::tokenizer::tokenizer(program).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+
+        assert!(matches!(
+            extract_name_map(program),
+            Err(SblError::Redefinition(..))
+        ));
+    }
 }
@@ -1,15 +1,108 @@
+use std::convert::TryFrom;
+
+use anyhow::bail;
+use num_bigint::BigInt;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+
+use crate::{error::SblError, span::Span};
+
+// Sized integers hold any value of their width, which can be wider than 64 bits
 #[derive(Debug, PartialEq, Clone)]
 pub enum NumericLiteral {
     Float(u8, f64),
 
     SysUint(u64),
-    Uint(u8, u64),
+    Uint(u16, BigInt),
     SysInt(i64),
-    Int(u8, i64),
+    Int(u16, BigInt),
 
     Boolean(bool),
 }
 
+impl NumericLiteral {
+    pub fn type_name(&self) -> String {
+        use NumericLiteral::*;
+        match self {
+            Float(size, _) => format!("F{}", size),
+            SysUint(_) => "Uint".to_string(),
+            Uint(size, _) => format!("U{}", size),
+            SysInt(_) => "Int".to_string(),
+            Int(size, _) => format!("I{}", size),
+            Boolean(_) => "Bool".to_string(),
+        }
+    }
+
+    pub fn as_index(&self) -> Option<usize> {
+        use NumericLiteral::*;
+        match self {
+            SysUint(n) => Some(*n as usize),
+            SysInt(n) if *n >= 0 => Some(*n as usize),
+            Uint(_, n) | Int(_, n) => n.to_usize(),
+            _ => None,
+        }
+    }
+
+    // The value without its type suffix
+    pub fn value_string(&self) -> String {
+        use NumericLiteral::*;
+        match self {
+            Float(width, n) => float_string(*width, *n),
+            SysUint(n) => n.to_string(),
+            SysInt(n) => n.to_string(),
+            Uint(_, n) | Int(_, n) => n.to_string(),
+            Boolean(b) => (*b as u8).to_string(),
+        }
+    }
+}
+
+// C's %g at the given number of significant digits
+fn general_format(n: f64, digits: usize) -> String {
+    let trim = |s: String| {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s
+        }
+    };
+
+    let scientific = format!("{:.*e}", digits - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    if exponent < -4 || exponent >= digits as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{}{:02}",
+            trim(mantissa.to_string()),
+            sign,
+            exponent.abs()
+        )
+    } else {
+        trim(format!("{:.*}", (digits as i32 - 1 - exponent) as usize, n))
+    }
+}
+
+// The fewest digits that read back as the same value, compiled code writes floats the same way
+fn float_string(width: u8, n: f64) -> String {
+    let n = if width == 32 { n as f32 as f64 } else { n };
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    let same = |s: &String| match width {
+        32 => s.parse::<f32>().ok() == Some(n as f32),
+        _ => s.parse::<f64>().ok() == Some(n),
+    };
+
+    (1..17)
+        .map(|digits| general_format(n, digits))
+        .find(same)
+        .unwrap_or_else(|| general_format(n, 17))
+}
+
 fn parse_atomic_floating_point(s: &str) -> anyhow::Result<f64> {
     Ok(s.parse::<f64>()?)
 }
@@ -18,41 +111,99 @@ fn parse_atomic_integer(s: &str) -> anyhow::Result<i64> {
     Ok(s.parse::<i64>()?)
 }
 
-fn parse_atomic_unsigned(s: &str) -> anyhow::Result<u64> {
-    Ok(s.parse::<u64>()?)
-}
-
 fn parse_f(s: &str) -> anyhow::Result<f64> {
     Ok(if let Some((a, b)) = s.split_once('E') {
         let a = parse_atomic_floating_point(a)?;
-        let b = parse_atomic_integer(b)?;
-        a * 10f64.powi(b as i32)
+        let b = i32::try_from(parse_atomic_integer(b)?)?;
+        a * 10f64.powi(b)
     } else {
         parse_atomic_floating_point(s)?
     })
 }
-fn parse_u(s: &str) -> anyhow::Result<u64> {
+
+// Integers are parsed exactly and checked against their width afterwards
+fn parse_integer(s: &str) -> anyhow::Result<BigInt> {
     Ok(if let Some((a, b)) = s.split_once('E') {
-        let a = parse_atomic_unsigned(a)?;
-        let b = parse_atomic_unsigned(b)?;
-        a * 10u64.pow(b as u32)
+        if b.starts_with('-') {
+            bail!("only floats can have a negative exponent");
+        }
+        let a = a.parse::<BigInt>()?;
+        let b = match b.parse::<u16>() {
+            Ok(b) => b,
+            Err(_) => bail!("E{} is too large", b),
+        };
+        a * BigInt::from(10).pow(b as u32)
     } else {
-        parse_atomic_unsigned(s)?
+        s.parse::<BigInt>()?
     })
 }
-fn parse_i(s: &str) -> anyhow::Result<i64> {
-    Ok(if let Some((a, b)) = s.split_once('E') {
-        let a = parse_atomic_integer(a)?;
-        let b = parse_atomic_unsigned(b)?;
-        a * 10i64.pow(b as u32)
+
+fn sys_int(n: BigInt) -> anyhow::Result<i64> {
+    match i64::try_from(&n) {
+        Ok(x) => Ok(x),
+        Err(_) => bail!("{} does not fit in 64 bits", n),
+    }
+}
+
+fn sys_uint(n: BigInt) -> anyhow::Result<u64> {
+    if n.is_negative() {
+        bail!("unsigned literals can not be negative");
+    }
+    match u64::try_from(&n) {
+        Ok(x) => Ok(x),
+        Err(_) => bail!("{} does not fit in 64 bits", n),
+    }
+}
+
+// 0x, 0o and 0b integers, their suffix starts at the first character that is not a digit
+fn parse_radix(s: &str) -> anyhow::Result<Option<NumericLiteral>> {
+    use NumericLiteral::*;
+
+    let (negative, unsigned) = match s.strip_prefix('-') {
+        Some(x) => (true, x),
+        None => (false, s),
+    };
+    let radix = match unsigned.get(..2) {
+        Some("0x") => 16,
+        Some("0o") => 8,
+        // 0b alone is the boolean false
+        Some("0b") if unsigned.len() > 2 => 2,
+        _ => return Ok(None),
+    };
+
+    let rest = &unsigned[2..];
+    let (digits, suffix) = rest.split_at(
+        rest.find(|x: char| !x.is_digit(radix))
+            .unwrap_or(rest.len()),
+    );
+    let n = match BigInt::parse_bytes(digits.as_bytes(), radix) {
+        Some(n) if negative => -n,
+        Some(n) => n,
+        None => bail!("expected digits after the base prefix"),
+    };
+
+    Ok(Some(match suffix {
+        "" | "i" => SysInt(sys_int(n)?),
+        "u" => SysUint(sys_uint(n)?),
+        _ if suffix.starts_with('i') => Int(suffix[1..].parse::<u16>()?, n),
+        _ if suffix.starts_with('u') => Uint(suffix[1..].parse::<u16>()?, n),
+        _ => bail!("{} is not an integer suffix", suffix),
+    }))
+}
+
+// The smallest value of an integer width and the first one past its largest
+pub fn int_bounds(width: u16, signed: bool) -> (BigInt, BigInt) {
+    if signed {
+        let half = BigInt::one() << (width as usize - 1);
+        (-half.clone(), half)
     } else {
-        parse_atomic_integer(s)?
-    })
+        (BigInt::zero(), BigInt::one() << width as usize)
+    }
 }
 
 enum ExtractSignatureAndVolumeResult {
     Signature(char),
-    SignatureAndVolume(char, u8),
+    SignatureAndVolume(char, u16),
 }
 
 fn extract_signature_and_volume_and_base(
@@ -89,40 +240,86 @@ fn extract_signature_and_volume_and_base(
                     Signature(signature)
                 }
             } else {
-                SignatureAndVolume(signature, volume.parse::<u8>()?)
+                SignatureAndVolume(signature, volume.parse::<u16>()?)
             },
         )),
         None => Ok((return_string, Signature('i'))),
     }
 }
 
-impl std::str::FromStr for NumericLiteral {
-    type Err = anyhow::Error;
+impl NumericLiteral {
+    pub fn parse(literal: &str, span: Span) -> Result<Self, SblError> {
+        let invalid = |err: anyhow::Error| SblError::InvalidLiteral {
+            literal: literal.to_string(),
+            reason: err.to_string(),
+            span,
+        };
+
+        // Underscores only separate digits
+        let cleaned: String = literal.chars().filter(|x| *x != '_').collect();
+        if let Some(n) = parse_radix(&cleaned).map_err(invalid)? {
+            return n.check_width().map_err(invalid);
+        }
 
-    fn from_str(s: &str) -> anyhow::Result<Self> {
-        let (s, vol_sig) = extract_signature_and_volume_and_base(s)?;
+        let (s, vol_sig) = extract_signature_and_volume_and_base(&cleaned).map_err(invalid)?;
 
         use ExtractSignatureAndVolumeResult::*;
         use NumericLiteral::*;
 
-        match vol_sig {
-            Signature('u') => Ok(SysUint(parse_u(s.as_str())?)),
-            Signature('i') => Ok(SysInt(parse_i(s.as_str())?)),
-            Signature('b') => Ok(Boolean(s != "0")),
+        let n = match vol_sig {
+            Signature('u') => SysUint(parse_integer(&s).and_then(sys_uint).map_err(invalid)?),
+            Signature('i') => SysInt(parse_integer(&s).and_then(sys_int).map_err(invalid)?),
+            Signature('b') => Boolean(s != "0"),
+
+            SignatureAndVolume('u', volume) => Uint(volume, parse_integer(&s).map_err(invalid)?),
+            SignatureAndVolume('i', volume) => Int(volume, parse_integer(&s).map_err(invalid)?),
+            SignatureAndVolume('f', volume) => Float(
+                u8::try_from(volume).map_err(|x| invalid(x.into()))?,
+                parse_f(s.as_str()).map_err(invalid)?,
+            ),
+
+            _ => return Err(SblError::UnimplementedLiteral(literal.to_string(), span)),
+        };
 
-            SignatureAndVolume('u', volume) => Ok(Uint(volume, parse_u(s.as_str())?)),
-            SignatureAndVolume('i', volume) => Ok(Int(volume, parse_i(s.as_str())?)),
-            SignatureAndVolume('f', volume) => Ok(Float(volume, parse_f(s.as_str())?)),
+        n.check_width().map_err(invalid)
+    }
 
-            _ => panic!("Unimplemented literal"),
+    // Rejects values the declared width can not hold
+    fn check_width(self) -> anyhow::Result<Self> {
+        use NumericLiteral::*;
+        match &self {
+            Int(0, _) | Uint(0, _) => bail!("integers are at least 1 bit wide"),
+            Uint(_, n) if n.is_negative() => bail!("unsigned literals can not be negative"),
+            Int(width, n) | Uint(width, n) => {
+                let (min, max) = int_bounds(*width, matches!(self, Int(..)));
+                if *n < min || *n >= max {
+                    bail!("{} does not fit in {} bits", n, width)
+                }
+            }
+            Float(width, _) if *width != 32 && *width != 64 => {
+                bail!("floats are 32 or 64 bits wide")
+            }
+            Float(_, n) if n.is_infinite() => bail!("value is too large for 64 bits"),
+            Float(32, n) if !(*n as f32).is_finite() => bail!("{} does not fit in 32 bits", n),
+            _ => (),
         }
+
+        Ok(self)
+    }
+}
+
+impl std::str::FromStr for NumericLiteral {
+    type Err = SblError;
+
+    fn from_str(s: &str) -> Result<Self, SblError> {
+        NumericLiteral::parse(s, Span::default())
     }
 }
 
 impl ToString for NumericLiteral {
     fn to_string(&self) -> String {
         use NumericLiteral::*;
-        match *self {
+        match self {
             Float(size, n) => format!("{}f{}", n, size),
             SysUint(n) => format!("{}u", n),
             SysInt(n) => format!("{}i", n),
@@ -133,3 +330,79 @@ impl ToString for NumericLiteral {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigInt;
+
+    use crate::numeric_litteral::NumericLiteral::{self, *};
+    use crate::span::Span;
+
+    fn parse(literal: &str) -> Option<NumericLiteral> {
+        NumericLiteral::parse(literal, Span::default()).ok()
+    }
+
+    #[test]
+    fn it_parses_bases_and_separators() {
+        assert_eq!(parse("0xff"), Some(SysInt(255)));
+        assert_eq!(parse("-0x10i8"), Some(Int(8, BigInt::from(-16))));
+        assert_eq!(parse("0o17u"), Some(SysUint(15)));
+        assert_eq!(parse("0b1010u8"), Some(Uint(8, BigInt::from(10))));
+        assert_eq!(parse("0b"), Some(Boolean(false)));
+        assert_eq!(parse("1_000_000"), Some(SysInt(1_000_000)));
+        assert_eq!(parse("1.5E-3d"), Some(Float(64, 1.5E-3)));
+        assert_eq!(parse("255u8"), Some(Uint(8, BigInt::from(255))));
+        assert_eq!(parse("-128i8"), Some(Int(8, BigInt::from(-128))));
+    }
+
+    #[test]
+    fn it_parses_wide_integers() {
+        assert_eq!(
+            parse("0xffff_ffff_ffff_ffff_ffff_ffff_ffff_ffffu128"),
+            Some(Uint(128, BigInt::from(u128::MAX)))
+        );
+        assert_eq!(
+            parse("-170141183460469231731687303715884105728i128"),
+            Some(Int(128, BigInt::from(i128::MIN)))
+        );
+        assert_eq!(parse("1E70u256"), Some(Uint(256, BigInt::from(10).pow(70))));
+        assert_eq!(parse("1E78u256"), None);
+        assert_eq!(parse("2E38i128"), None);
+        assert_eq!(parse("-1u128"), None);
+    }
+
+    #[test]
+    fn it_writes_floats_like_printf() {
+        for (n, expected) in [
+            (Float(64, 1.5), "1.5"),
+            (Float(64, 0.1 + 0.2), "0.30000000000000004"),
+            (Float(64, 1E20), "1e+20"),
+            (Float(64, 123456.0), "123456"),
+            (Float(64, 0.0001), "0.0001"),
+            (Float(64, -2.5E-5), "-2.5e-05"),
+            (Float(64, -0.0), "-0"),
+            (Float(32, 1.1), "1.1"),
+            (Float(64, f64::NEG_INFINITY), "-inf"),
+        ]
+        .iter()
+        {
+            assert_eq!(n.value_string(), *expected);
+        }
+    }
+
+    #[test]
+    fn it_rejects_values_wider_than_their_type() {
+        assert_eq!(parse("300u8"), None);
+        assert_eq!(parse("128i8"), None);
+        assert_eq!(parse("-1u"), None);
+        assert_eq!(parse("0x1g"), None);
+        assert_eq!(parse("1E-3"), None);
+        assert_eq!(parse("1i0"), None);
+        assert_eq!(parse("1f16"), None);
+        assert_eq!(parse("1E300f32"), None);
+        assert_eq!(parse("1E19"), None);
+        assert_eq!(parse("2E19u"), None);
+        assert_eq!(parse("1E400d"), None);
+        assert_eq!(parse("1E4294967296"), None);
+    }
+}
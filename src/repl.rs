@@ -1,8 +1,218 @@
-use inkwell::{context::Context, passes::PassManager, values::FunctionValue};
+use inkwell::{
+    context::Context, execution_engine::ExecutionEngine, module::Module, passes::PassManager,
+    values::FunctionValue, OptimizationLevel,
+};
 
+use std::collections::HashSet;
 use std::io::Write;
 
-use crate::tokenizer::{tokenizer, Token};
+use anyhow::anyhow;
+
+use crate::{
+    ast::{build_expression, build_tree, ASTNode, TopLevelNode},
+    compiler::{cell_literal, literal_widths, show_cell, tag, Cell, Compiler},
+    diagnostic::Sources,
+    modules::resolve_imports,
+    namemap::{extract_name_map, validate, warnings, NameMap},
+    span::Spanned,
+    tokenizer::{tokenizer, Token},
+    typecheck::{check_expression, check_name_map, Effect, Ty},
+};
+
+// Pointees are unknown, each pointer gets its own variable
+fn cell_type((i, cell): (usize, &Cell)) -> Ty {
+    match cell.tag as u64 >> 16 {
+        tag::STR => Ty::Named("Str".to_string()),
+        tag::PTR => Ty::Ptr(Box::new(Ty::Var(i + 1))),
+        tag::QUOTE => Ty::Quote(Box::new(Effect::unknown())),
+        tag::LIST => Ty::Named("List".to_string()),
+        _ => match cell_literal(cell) {
+            Some(n) => Ty::Named(n.type_name()),
+            None => Ty::Quote(Box::new(Effect::unknown())),
+        },
+    }
+}
+
+fn is_balanced(tokens: &[Spanned<Token>]) -> bool {
+    let mut depth = 0;
+
+    for token in tokens {
+        match token.node {
+            Token::Square(true) | Token::Curly(true) => depth += 1,
+            Token::Square(false) | Token::Curly(false) => depth -= 1,
+            _ => (),
+        }
+    }
+
+    depth <= 0
+}
+
+struct Session<'ctx> {
+    context: &'ctx Context,
+    name_map: NameMap,
+    stack: Vec<Cell>,
+
+    // Every line is its own module, they are kept alive since the stack can point into them
+    engines: Vec<(Module<'ctx>, ExecutionEngine<'ctx>)>,
+
+    // Of the line being evaluated and the files it imports
+    sources: Sources,
+}
+
+impl<'ctx> Session<'ctx> {
+    fn run(
+        &mut self,
+        name_map: NameMap,
+        expression: &[Spanned<ASTNode>],
+        debug: bool,
+    ) -> anyhow::Result<()> {
+        let module = self
+            .context
+            .create_module(&format!("repl.{}", self.engines.len()));
+        let builder = self.context.create_builder();
+
+        let cmanager: PassManager<FunctionValue> = PassManager::create(&module);
+
+        cmanager.add_instruction_combining_pass();
+        cmanager.add_reassociate_pass();
+        cmanager.add_gvn_pass();
+        cmanager.add_cfg_simplification_pass();
+        cmanager.add_basic_alias_analysis_pass();
+        cmanager.add_promote_memory_to_register_pass();
+        cmanager.add_instruction_combining_pass();
+        cmanager.add_reassociate_pass();
+
+        cmanager.initialize();
+
+        {
+            let mut compiler =
+                Compiler::new(self.context, &builder, &cmanager, &module, name_map.clone());
+            // The stack and the line can hold integers of widths the definitions never build
+            compiler.int_widths.extend(
+                self.stack
+                    .iter()
+                    .filter(|x| matches!(x.tag as u64 >> 16, tag::INT | tag::UINT))
+                    .map(|x| x.tag as u64 & 0xffff),
+            );
+            literal_widths(expression, &mut compiler.int_widths);
+            compiler.compile()?;
+            compiler.compile_expression("sbl.expr", expression)?;
+        }
+
+        if debug {
+            module.print_to_stderr();
+        }
+
+        let engine = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|x| anyhow!(x.to_string()))?;
+
+        unsafe {
+            let stack = engine.get_global_address("sbl.stack")? as *mut Cell;
+            let sp = engine.get_global_address("sbl.sp")? as *mut u64;
+
+            for (i, cell) in self.stack.iter().enumerate() {
+                stack.add(i).write(*cell);
+            }
+            sp.write(self.stack.len() as u64);
+
+            engine
+                .get_function::<unsafe extern "C" fn()>("sbl.expr")?
+                .call();
+
+            self.stack = (0..sp.read() as usize)
+                .map(|i| stack.add(i).read())
+                .collect();
+        }
+
+        self.name_map = name_map;
+        self.engines.push((module, engine));
+
+        Ok(())
+    }
+
+    fn eval(
+        &mut self,
+        source: &str,
+        tokens: Vec<Spanned<Token>>,
+        debug_ast_out: bool,
+        debug_comp_out: bool,
+    ) -> anyhow::Result<()> {
+        let mut name_map = self.name_map.clone();
+        self.sources = Sources::new("<repl>", source);
+
+        let is_definition = match tokens
+            .iter()
+            .map(|x| &x.node)
+            .find(|x| !matches!(x, Token::Comment(_)))
+        {
+            Some(Token::AtSign) | Some(Token::QMark) => true,
+            Some(Token::Ident(x)) => x == "use",
+            _ => false,
+        };
+
+        let expression = if is_definition {
+            // Imports are resolved relative to the working directory
+            let tree = resolve_imports(build_tree(tokens)?, &mut self.sources)?;
+            if debug_ast_out {
+                println!("{:?}", tree);
+            }
+
+            let defined: HashSet<String> = tree
+                .iter()
+                .filter_map(|x| match &x.node {
+                    TopLevelNode::WordDeclare(name, _) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            let definitions = extract_name_map(tree)?;
+            for warning in warnings(&definitions) {
+                println!("{}", self.sources.render_warning(&warning));
+            }
+            // Builtins and the prelude come along, they must not undo earlier redefinitions
+            let definitions: Vec<_> = definitions
+                .into_iter()
+                .filter(|(name, _)| defined.contains(name) || !name_map.contains_key(name))
+                .collect();
+            name_map.extend(definitions);
+
+            let errors = validate(&name_map);
+            if !errors.is_empty() {
+                for error in errors.iter() {
+                    println!("{}", self.sources.render(&error.diagnostic()));
+                }
+                return Err(anyhow!("Definitions rejected"));
+            }
+
+            let errors = check_name_map(&name_map);
+            if !errors.is_empty() {
+                for error in errors.iter() {
+                    println!("{}", self.sources.render(&error.diagnostic()));
+                }
+                return Err(anyhow!("Definitions rejected"));
+            }
+
+            Vec::new()
+        } else {
+            let expression = build_expression(tokens)?;
+            if debug_ast_out {
+                println!("{:?}", expression);
+            }
+
+            check_expression(
+                &name_map,
+                self.stack.iter().enumerate().map(cell_type).collect(),
+                &expression,
+            )
+            .map_err(|x| x.diagnostic())?;
+
+            expression
+        };
+
+        self.run(name_map, &expression, debug_comp_out)
+    }
+}
 
 pub fn repl() {
     let debug_lexer_out = false;
@@ -10,38 +220,65 @@ pub fn repl() {
     let debug_comp_out = false;
 
     let ccontext = Context::create();
-    let module = ccontext.create_module("repl");
-    let builder = ccontext.create_builder();
 
-    let cmanager: PassManager<FunctionValue> = PassManager::create(&module);
+    let mut session = Session {
+        context: &ccontext,
+        name_map: extract_name_map(Vec::new()).expect("invalid prelude"),
+        stack: Vec::new(),
+        engines: Vec::new(),
+        sources: Sources::default(),
+    };
 
-    cmanager.add_instruction_combining_pass();
-    cmanager.add_reassociate_pass();
-    cmanager.add_gvn_pass();
-    cmanager.add_cfg_simplification_pass();
-    cmanager.add_basic_alias_analysis_pass();
-    cmanager.add_promote_memory_to_register_pass();
-    cmanager.add_instruction_combining_pass();
-    cmanager.add_reassociate_pass();
-
-    cmanager.initialize();
-
-    let mut accumulator: Vec<Token> = Vec::new();
+    // Lines are buffered until their brackets balance
+    let mut accumulator = String::new();
 
     loop {
         println!();
-        print!("sbl > ");
+        if accumulator.is_empty() {
+            print!("sbl > ");
+        } else {
+            print!("..... ");
+        }
         std::io::stdout().flush().unwrap();
 
         let mut s = String::new();
-        std::io::stdin().read_line(&mut s).unwrap();
+        if std::io::stdin().read_line(&mut s).unwrap() == 0 {
+            break;
+        }
+
+        let line_start = accumulator.len();
+        accumulator.push_str(&s);
 
-        match tokenizer(s) {
-            Ok(res) => (),
+        let tokens = match tokenizer(accumulator.clone()) {
+            Ok(res) => {
+                if debug_lexer_out {
+                    println!("{:?}", res);
+                }
+                res
+            }
             Err(err) => {
-                println!("A tokenizer error occurred:\n{}\nThis means the expression is not added to the token buffer", err);
+                println!("A tokenizer error occurred:\n{}\nThis means the line is not added to the buffer", err.diagnostic().render(&accumulator, "<repl>"));
+                accumulator.truncate(line_start);
                 continue;
             }
         };
+
+        if !is_balanced(&tokens) {
+            continue;
+        }
+
+        let source = std::mem::take(&mut accumulator);
+        match session.eval(&source, tokens, debug_ast_out, debug_comp_out) {
+            Ok(()) => println!(
+                "[{}]",
+                session
+                    .stack
+                    .iter()
+                    .map(show_cell)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            Err(err) => println!("{}", session.sources.render_error(&err)),
+        }
     }
 }
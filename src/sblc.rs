@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail};
+use inkwell::{
+    context::Context,
+    module::{Linkage, Module},
+    passes::PassManager,
+    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
+    types::BasicType,
+    values::FunctionValue,
+    OptimizationLevel,
+};
+
+use crate::{compiler::Compiler, diagnostic::Sources};
+
+fn usage() -> anyhow::Error {
+    anyhow!("Usage: sblc <input.sbl> [-o <output>]")
+}
+
+fn native_machine() -> anyhow::Result<TargetMachine> {
+    Target::initialize_native(&InitializationConfig::default()).map_err(|x| anyhow!(x))?;
+
+    let triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&triple).map_err(|x| anyhow!(x.to_string()))?;
+
+    match target.create_target_machine(
+        &triple,
+        &TargetMachine::get_host_cpu_name().to_string(),
+        &TargetMachine::get_host_cpu_features().to_string(),
+        OptimizationLevel::Default,
+        RelocMode::PIC,
+        CodeModel::Default,
+    ) {
+        Some(machine) => Ok(machine),
+        None => bail!("Unable to create a target machine for {:?}", triple),
+    }
+}
+
+// int main() { word main; return 0; }
+fn emit_entry_point<'ctx>(
+    compiler: &Compiler<'ctx, '_>,
+    module: &Module<'ctx>,
+) -> anyhow::Result<()> {
+    let word = match compiler.name_exec_map.get("main") {
+        Some(x) => *x,
+        None => bail!("Program has no @main word"),
+    };
+
+    let i32_type = compiler.context.i32_type();
+    let main = module.add_function(
+        "main",
+        i32_type.fn_type(&[], false),
+        Some(Linkage::External),
+    );
+    let entry = compiler.context.append_basic_block(main, "entry");
+
+    compiler.builder.position_at_end(entry);
+    compiler.builder.build_call(word, &[], "");
+    compiler.builder.build_return(Some(&i32_type.const_zero()));
+
+    if main.verify(true) {
+        Ok(())
+    } else {
+        bail!("Generated invalid entry point")
+    }
+}
+
+pub fn compile_file(input: &Path, output: &Path) -> anyhow::Result<()> {
+    let source = std::fs::read_to_string(input)?;
+    let path = input.to_string_lossy();
+
+    let program = crate::tokenizer::tokenizer(source.clone())
+        .map_err(|err| anyhow!(err.diagnostic().render(&source, &path)))?;
+
+    let (program, errors) = crate::ast::build_tree_recovering(program);
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("{}\n", error.diagnostic().render(&source, &path));
+        }
+        bail!("{} syntax error(s) in {}", errors.len(), input.display())
+    }
+
+    let mut sources = Sources::new(&path, &source);
+    let program = crate::modules::resolve_imports(program, &mut sources)
+        .map_err(|err| anyhow!(err.render()))?;
+
+    let (mut program, mut errors) = crate::namemap::extract_name_map_recovering(program);
+    errors.extend(crate::namemap::validate(&program));
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("{}\n", sources.render(&error.diagnostic()));
+        }
+        bail!("{} name error(s) in {}", errors.len(), input.display())
+    }
+
+    for warning in crate::namemap::warnings(&program) {
+        eprintln!("{}\n", sources.render_warning(&warning));
+    }
+
+    let errors = crate::typecheck::check_name_map(&program);
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("{}\n", sources.render(&error.diagnostic()));
+        }
+        bail!("{} type error(s) in {}", errors.len(), input.display())
+    }
+
+    crate::callgraph::eliminate_dead_words(&mut program, "main");
+
+    let context = Context::create();
+    let module = context.create_module(&input.to_string_lossy());
+    let builder = context.create_builder();
+
+    let fpm: PassManager<FunctionValue> = PassManager::create(&module);
+
+    fpm.add_instruction_combining_pass();
+    fpm.add_reassociate_pass();
+    fpm.add_gvn_pass();
+    fpm.add_cfg_simplification_pass();
+    fpm.add_basic_alias_analysis_pass();
+    fpm.add_promote_memory_to_register_pass();
+    fpm.add_instruction_combining_pass();
+    fpm.add_reassociate_pass();
+
+    fpm.initialize();
+
+    let mut compiler = Compiler::new(&context, &builder, &fpm, &module, program);
+    compiler
+        .compile()
+        .map_err(|err| anyhow!(sources.render_error(&err)))?;
+    emit_entry_point(&compiler, &module)?;
+
+    let machine = native_machine()?;
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+
+    module.verify().map_err(|x| anyhow!(x.to_string()))?;
+
+    let object = output.with_extension("o");
+    machine
+        .write_to_file(&module, FileType::Object, &object)
+        .map_err(|x| anyhow!(x.to_string()))?;
+
+    let status = Command::new("cc")
+        .arg(&object)
+        .arg("-o")
+        .arg(output)
+        .status()?;
+    std::fs::remove_file(&object)?;
+
+    if !status.success() {
+        bail!("Linking {} failed with {}", output.display(), status)
+    }
+
+    Ok(())
+}
+
+// sblc <input.sbl> [-o <output>]
+pub fn sblc(args: &[String]) -> anyhow::Result<()> {
+    let mut input = None;
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(usage()),
+        }
+    }
+
+    let input = input.ok_or_else(usage)?;
+    let output = output.unwrap_or_else(|| input.with_extension(""));
+
+    compile_file(&input, &output)
+}
@@ -0,0 +1,35 @@
+// Byte range into the source, line and column are those of `start` and count from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub file: usize, // Index into the program's `Sources`
+}
+
+impl Span {
+    // The span covering both self and other
+    pub fn to(self, other: Span) -> Span {
+        if other.start < self.start {
+            other.to(self)
+        } else {
+            Span {
+                end: self.end.max(other.end),
+                ..self
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span }
+    }
+}
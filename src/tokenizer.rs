@@ -1,6 +1,7 @@
-use core::panic;
+use crate::error::SblError;
+use crate::span::{Span, Spanned};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Comment(String),
     Ident(String),
@@ -17,26 +18,163 @@ pub enum Token {
     Square(bool), // True = open, False = close
     Curly(bool),  // True = open, False = close
 }
-type CharStream<'a> = std::iter::Peekable<std::str::Chars<'a>>;
 
-fn escape_char(s: &mut CharStream) -> char {
-    s.next();
-    match s.peek() {
-        Some('n') => '\n',
-        Some('r') => '\r',
-        Some('\n') => '\n',
-        Some(x) => {
-            println!("{}", x);
-            todo!()
+#[derive(Clone)]
+struct CharStream<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    offset: usize,
+    line: usize,
+    column: usize,
+    file: usize,
+}
+
+impl<'a> CharStream<'a> {
+    fn new(input: &'a str, file: usize) -> Self {
+        Self {
+            chars: input.chars().peekable(),
+            offset: 0,
+            line: 1,
+            column: 1,
+            file,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    fn start(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            column: self.column,
+            file: self.file,
         }
-        _ => todo!(),
     }
+
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.offset,
+            ..start
+        }
+    }
+}
+
+impl<'a> Iterator for CharStream<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let x = self.chars.next()?;
+
+        self.offset += x.len_utf8();
+        if x == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        Some(x)
+    }
+}
+
+fn hex_digits(s: &mut CharStream, count: usize) -> String {
+    let mut out = String::new();
+    while let Some(x) = s.peek() {
+        if out.len() == count || !x.is_ascii_hexdigit() {
+            break;
+        }
+        out.push(*x);
+        s.next();
+    }
+    out
+}
+
+// Consumes a whole escape sequence, a line continuation escapes to nothing
+fn escape_char(s: &mut CharStream) -> Result<Option<char>, SblError> {
+    let start = s.start();
+    s.next();
+    let x = match s.next() {
+        Some(x) => x,
+        None => return Err(SblError::UnterminatedString(start)),
+    };
+
+    let escaped = match x {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        'x' => {
+            let digits = hex_digits(s, 2);
+            match u8::from_str_radix(&digits, 16) {
+                Ok(n) if digits.len() == 2 && n <= 0x7f => n as char,
+                _ => {
+                    return Err(SblError::InvalidEscape(
+                        "\\x takes two hex digits up to 7f".to_string(),
+                        s.span_from(start),
+                    ))
+                }
+            }
+        }
+        'u' => {
+            let digits = match s.peek() {
+                Some('{') => {
+                    s.next();
+                    let digits = hex_digits(s, 6);
+                    match s.next() {
+                        Some('}') => Some(digits),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            let code = digits
+                .filter(|x| !x.is_empty())
+                .and_then(|x| u32::from_str_radix(&x, 16).ok());
+            match code.map(std::char::from_u32) {
+                Some(Some(x)) => x,
+                Some(None) => {
+                    return Err(SblError::InvalidEscape(
+                        "\\u{...} is not a unicode scalar value".to_string(),
+                        s.span_from(start),
+                    ))
+                }
+                None => {
+                    return Err(SblError::InvalidEscape(
+                        "\\u takes one to six hex digits in braces".to_string(),
+                        s.span_from(start),
+                    ))
+                }
+            }
+        }
+        // Skips the newline and the indentation of the next line, blank lines are kept
+        '\r' | '\n' if x == '\n' || matches!(s.peek(), Some('\n')) => {
+            if x == '\r' {
+                s.next();
+            }
+            while let Some(' ' | '\t') = s.peek() {
+                s.next();
+            }
+            return Ok(None);
+        }
+        x => return Err(SblError::UnknownEscape(x, s.span_from(start))),
+    };
+
+    Ok(Some(escaped))
 }
 
 fn numeric_tokenize(s: &mut CharStream) -> Token {
     let mut out = String::new();
 
-    while let Some(x @ (('0'..='9') | '-' | '.' | 'E' | 'd' | 'b' | 'f' | 'i' | 'u')) = s.peek() {
+    // Suffixes, exponents, base prefixes, hex digits and separators
+    while let Some(
+        x @ (('0'..='9') | ('a'..='f') | ('A'..='F') | '-' | '.' | '_' | 'i' | 'o' | 'u' | 'x'),
+    ) = s.peek()
+    {
         out.push(*x);
         s.next();
     }
@@ -44,33 +182,176 @@ fn numeric_tokenize(s: &mut CharStream) -> Token {
     Token::NumericLiteral(out)
 }
 
-fn char_tokenizer(s: &mut CharStream) -> Token {
+fn char_tokenizer(s: &mut CharStream) -> Result<Token, SblError> {
+    let start = s.start();
     s.next();
 
     let v = match s.peek() {
-        Some('\\') => Token::CharLiteral(escape_char(s)),
-        Some(x) => Token::CharLiteral(*x),
-        _ => panic!("Unimplemented"),
+        Some('\\') => match escape_char(s)? {
+            Some(x) => Token::CharLiteral(x),
+            None => return Err(SblError::EmptyChar(s.span_from(start))),
+        },
+        Some('\'') => return Err(SblError::EmptyChar(s.span_from(start))),
+        Some(&x) => {
+            s.next();
+            Token::CharLiteral(x)
+        }
+        None => return Err(SblError::UnterminatedChar(s.span_from(start))),
     };
-    s.next();
-    v
+
+    match s.peek() {
+        Some('\'') => Ok(v),
+        _ => Err(SblError::UnterminatedChar(s.span_from(start))),
+    }
 }
 
-fn string_tokenizer(s: &mut CharStream) -> Token {
+fn string_tokenizer(s: &mut CharStream) -> Result<Token, SblError> {
+    let start = s.start();
     s.next();
     let mut string = String::new();
 
     loop {
         match s.peek() {
             Some('"') => break,
-            Some('\\') => string.push(escape_char(s)),
+            Some('\\') => {
+                string.extend(escape_char(s)?);
+                continue;
+            }
             Some(x) => string.push(*x),
-            None => todo!(),
+            None => return Err(SblError::UnterminatedString(start)),
         }
         s.next();
     }
 
-    Token::StringLiteral(string)
+    Ok(Token::StringLiteral(string))
+}
+
+fn triple_quote(s: &CharStream) -> bool {
+    let mut ahead = s.clone();
+    (0..3).all(|_| ahead.next() == Some('"'))
+}
+
+fn line_end(s: &CharStream) -> bool {
+    let mut ahead = s.clone();
+    ahead.next() == Some('\r') && ahead.next() == Some('\n')
+}
+
+// Lines are stripped of the indentation they have in common, blank first and last lines are
+// dropped so the quotes can sit on lines of their own
+fn multiline_tokenizer(s: &mut CharStream) -> Result<Token, SblError> {
+    let start = s.start();
+    for _ in 0..3 {
+        s.next();
+    }
+
+    // The whitespace each line starts with in the source and the rest of the line
+    let mut lines = vec![(String::new(), String::new())];
+    let mut at_indent = true;
+
+    loop {
+        if triple_quote(s) {
+            break;
+        }
+
+        let crlf = line_end(s);
+        let line = lines.last_mut().unwrap();
+        match s.peek() {
+            Some('\\') => {
+                line.1.extend(escape_char(s)?);
+                at_indent = false;
+                continue;
+            }
+            Some('\n') => {
+                lines.push(Default::default());
+                at_indent = true;
+            }
+            Some('\r') if crlf => {}
+            Some(&x @ (' ' | '\t' | '\r')) if at_indent => line.0.push(x),
+            Some(&x) => {
+                line.1.push(x);
+                at_indent = false;
+            }
+            None => return Err(SblError::UnterminatedString(start)),
+        }
+        s.next();
+    }
+    for _ in 0..3 {
+        s.next();
+    }
+
+    if lines.len() > 1 && lines[0].1.is_empty() {
+        lines.remove(0);
+    }
+    if lines.len() > 1 && lines[lines.len() - 1].1.is_empty() {
+        lines.pop();
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|(_, rest)| !rest.is_empty())
+        .map(|(indent, _)| indent.chars().count())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<String> = lines
+        .into_iter()
+        .map(|(whitespace, rest)| {
+            if rest.is_empty() {
+                rest
+            } else {
+                whitespace
+                    .chars()
+                    .skip(indent)
+                    .chain(rest.chars())
+                    .collect()
+            }
+        })
+        .collect();
+
+    Ok(Token::StringLiteral(lines.join("\n")))
+}
+
+fn raw_string_start(s: &CharStream) -> bool {
+    let mut ahead = s.clone();
+    ahead.next();
+    while let Some('#') = ahead.peek() {
+        ahead.next();
+    }
+    ahead.peek() == Some(&'"')
+}
+
+// r"..." with no escapes, r#"..."# and so on can hold quotes
+fn raw_string_tokenizer(s: &mut CharStream) -> Result<Token, SblError> {
+    let start = s.start();
+    s.next();
+    let mut hashes = 0;
+    while let Some('#') = s.peek() {
+        hashes += 1;
+        s.next();
+    }
+    s.next();
+
+    let mut string = String::new();
+    loop {
+        match s.next() {
+            Some('"') => {
+                let mut ahead = s.clone();
+                let mut closing = 0;
+                while closing < hashes && ahead.peek() == Some(&'#') {
+                    closing += 1;
+                    ahead.next();
+                }
+                if closing == hashes {
+                    *s = ahead;
+                    break;
+                }
+                string.push('"');
+            }
+            Some(x) => string.push(x),
+            None => return Err(SblError::UnterminatedString(start)),
+        }
+    }
+
+    Ok(Token::StringLiteral(string))
 }
 
 fn ident_tokenizer(s: &mut CharStream) -> Token {
@@ -102,11 +383,16 @@ fn comment_tokenizer(s: &mut CharStream) -> Token {
     Token::Comment(string)
 }
 
-pub fn tokenizer(input: String) -> anyhow::Result<Vec<Token>> {
+pub fn tokenizer(input: String) -> Result<Vec<Spanned<Token>>, SblError> {
+    tokenizer_in(input, 0)
+}
+
+// Spans of the tokens point into the source file of the given index
+pub fn tokenizer_in(input: String, file: usize) -> Result<Vec<Spanned<Token>>, SblError> {
     let mut out = Vec::new();
 
-    let mut stream: CharStream = input.chars().peekable();
-    while let Some(x) = stream.peek() {
+    let mut stream = CharStream::new(&input, file);
+    while let Some(&x) = stream.peek() {
         if x.is_whitespace() {
             stream.next();
 
@@ -116,8 +402,9 @@ pub fn tokenizer(input: String) -> anyhow::Result<Vec<Token>> {
             //     }
             // }
         } else {
-            // TODO: Comments
-            out.push(match x {
+            let start = stream.start();
+
+            let token = match x {
                 '$' => Token::Dollar,
                 ':' => Token::Colon,
                 '#' => Token::Octothorp,
@@ -130,18 +417,52 @@ pub fn tokenizer(input: String) -> anyhow::Result<Vec<Token>> {
                 ']' => Token::Square(false),
 
                 ';' => comment_tokenizer(&mut stream),
-                '"' => string_tokenizer(&mut stream),
-                '\'' => char_tokenizer(&mut stream),
-                '0'..='9' | '-' => {
-                    out.push(numeric_tokenize(&mut stream));
+                '"' if triple_quote(&stream) => {
+                    let token = multiline_tokenizer(&mut stream)?;
+                    out.push(Spanned::new(token, stream.span_from(start)));
+                    continue;
+                }
+                '"' => string_tokenizer(&mut stream)?,
+                '\'' => char_tokenizer(&mut stream)?,
+                'r' if raw_string_start(&stream) => {
+                    let token = raw_string_tokenizer(&mut stream)?;
+                    out.push(Spanned::new(token, stream.span_from(start)));
+                    continue;
+                }
+                '0'..='9' => {
+                    // Words like 2dup start out as a literal but go on past its end
+                    let mut ahead = stream.clone();
+                    numeric_tokenize(&mut ahead);
+                    let token = match ahead.peek() {
+                        Some(x) if !x.is_whitespace() && !"[]{}".contains(*x) => {
+                            ident_tokenizer(&mut stream)
+                        }
+                        _ => numeric_tokenize(&mut stream),
+                    };
+                    out.push(Spanned::new(token, stream.span_from(start)));
+                    continue;
+                }
+                '-' => {
+                    let mut ahead = stream.clone();
+                    ahead.next();
+
+                    let token = if let Some('0'..='9') = ahead.peek() {
+                        numeric_tokenize(&mut stream)
+                    } else {
+                        ident_tokenizer(&mut stream)
+                    };
+                    out.push(Spanned::new(token, stream.span_from(start)));
                     continue;
                 }
                 _ => {
-                    out.push(ident_tokenizer(&mut stream));
+                    let token = ident_tokenizer(&mut stream);
+                    out.push(Spanned::new(token, stream.span_from(start)));
                     continue;
                 }
-            });
+            };
             stream.next();
+
+            out.push(Spanned::new(token, stream.span_from(start)));
         }
     }
 
@@ -150,7 +471,9 @@ pub fn tokenizer(input: String) -> anyhow::Result<Vec<Token>> {
 
 #[cfg(test)]
 mod tests {
-    use crate::tokenizer::{tokenizer, Token};
+    use crate::error::SblError;
+    use crate::span::Span;
+    use crate::tokenizer::{tokenizer, tokenizer_in, Token};
 
     #[test]
     fn it_escape_chars_in_strings() {
@@ -160,13 +483,13 @@ mod tests {
 
         let goal = "Hello world!\n".to_string();
 
-        if let Token::StringLiteral(x) = &result[0] {
+        if let Token::StringLiteral(x) = &result[0].node {
             assert_eq!(x.clone(), goal);
         } else {
             panic!("Failed to escape")
         }
 
-        if let Token::StringLiteral(x) = &result[1] {
+        if let Token::StringLiteral(x) = &result[1].node {
             assert_eq!(x.clone(), goal);
         } else {
             panic!("Failed to escape")
@@ -181,19 +504,191 @@ mod tests {
 
         let goal = '\n';
 
-        if let Token::CharLiteral(x) = &result[0] {
+        if let Token::CharLiteral(x) = &result[0].node {
             assert_eq!(*x, goal);
         } else {
             panic!("Failed to escape")
         }
 
-        if let Token::CharLiteral(x) = &result[1] {
+        if let Token::CharLiteral(x) = &result[1].node {
             assert_eq!(*x, goal);
         } else {
             panic!("Failed to escape")
         }
     }
 
+    #[test]
+    fn it_escapes_the_full_set() {
+        let program = r#""\\ \" \t \0 \x41 \u{e9} \u{1F600} \
+            next" '\'' '\u{3bb}'"#;
+
+        let result = tokenizer(program.to_string()).unwrap();
+
+        assert_eq!(
+            result[0].node,
+            Token::StringLiteral("\\ \" \t \0 A \u{e9} \u{1F600} next".to_string())
+        );
+        assert_eq!(result[1].node, Token::CharLiteral('\''));
+        assert_eq!(result[2].node, Token::CharLiteral('\u{3bb}'));
+    }
+
+    #[test]
+    fn it_continues_lines() {
+        for (program, expected) in [
+            ("\"a\\\n    b\"", "ab"),
+            ("\"a\\\r\n\tb\"", "ab"),
+            ("\"a\\\n\n  b\"", "a\n  b"),
+            ("\"a\\\r\n  \r\nb\"", "a\r\nb"),
+        ]
+        .iter()
+        {
+            let result = tokenizer(program.to_string()).unwrap();
+            assert_eq!(
+                result[0].node,
+                Token::StringLiteral(expected.to_string()),
+                "{:?}",
+                program
+            );
+        }
+
+        assert!(matches!(
+            tokenizer("\"a\\\rb\"".to_string()),
+            Err(SblError::UnknownEscape('\r', _))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_malformed_escapes() {
+        for program in [
+            r#""\x4""#,
+            r#""\x80""#,
+            r#""\u41""#,
+            r#""\u{}""#,
+            r#""\u{d800}""#,
+            r#""\u{1234567}""#,
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(
+                    tokenizer(program.to_string()),
+                    Err(SblError::InvalidEscape(..))
+                ),
+                "{}",
+                program
+            );
+        }
+        assert!(matches!(
+            tokenizer("'\\\n'".to_string()),
+            Err(SblError::EmptyChar(_))
+        ));
+    }
+
+    #[test]
+    fn it_reads_raw_strings() {
+        let program = r####"r"\d+\n" r#"{"a": "b"}"# r##""# "## rot r"####;
+
+        let result = tokenizer(program.to_string()).unwrap();
+
+        assert_eq!(result[0].node, Token::StringLiteral(r"\d+\n".to_string()));
+        assert_eq!(
+            result[1].node,
+            Token::StringLiteral(r#"{"a": "b"}"#.to_string())
+        );
+        assert_eq!(result[2].node, Token::StringLiteral("\"# ".to_string()));
+        assert_eq!(result[3].node, Token::Ident("rot".to_string()));
+        assert_eq!(result[4].node, Token::Ident("r".to_string()));
+        assert_eq!(
+            &program[result[1].span.start..result[1].span.end],
+            r##"r#"{"a": "b"}"#"##
+        );
+
+        assert!(matches!(
+            tokenizer("r#\"abc\"".to_string()),
+            Err(SblError::UnterminatedString(Span { start: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn it_strips_multiline_indentation() {
+        let program = "@help \"\"\"
+            usage: sbl [file]
+
+              -h\tshows \"this\"
+            \"\"\" \"\"\"one\\n\"\"\" \"\"\"\"\"\"";
+
+        let result = tokenizer(program.to_string()).unwrap();
+
+        assert_eq!(
+            result[2].node,
+            Token::StringLiteral("usage: sbl [file]\n\n  -h\tshows \"this\"".to_string())
+        );
+        assert_eq!(result[3].node, Token::StringLiteral("one\n".to_string()));
+        assert_eq!(result[4].node, Token::StringLiteral(String::new()));
+
+        let result = tokenizer("\"\"\"\r\n  a\r\n\r\n  b\r\n  \"\"\"".to_string()).unwrap();
+        assert_eq!(result[0].node, Token::StringLiteral("a\n\nb".to_string()));
+        assert!(matches!(
+            tokenizer("\"\"\"abc\"\"".to_string()),
+            Err(SblError::UnterminatedString(_))
+        ));
+    }
+
+    #[test]
+    fn it_tracks_spans() {
+        let program = "@main {\n  \"hi\" . }";
+
+        let result = tokenizer_in(program.to_string(), 2).unwrap();
+
+        assert_eq!(
+            result[3].span,
+            Span {
+                start: 10,
+                end: 14,
+                line: 2,
+                column: 3,
+                file: 2,
+            }
+        );
+        assert_eq!(&program[result[4].span.start..result[4].span.end], ".");
+    }
+
+    #[test]
+    fn it_rejects_bad_literals() {
+        assert!(matches!(
+            tokenizer("\"abc".to_string()),
+            Err(SblError::UnterminatedString(Span { start: 0, .. }))
+        ));
+        assert!(matches!(
+            tokenizer("\"\\q\"".to_string()),
+            Err(SblError::UnknownEscape(
+                'q',
+                Span {
+                    start: 1,
+                    end: 3,
+                    ..
+                }
+            ))
+        ));
+        assert!(matches!(
+            tokenizer("'ab'".to_string()),
+            Err(SblError::UnterminatedChar(_))
+        ));
+        assert!(matches!(
+            tokenizer("''".to_string()),
+            Err(SblError::EmptyChar(_))
+        ));
+    }
+
+    #[test]
+    fn it_reads_words_starting_with_digits() {
+        let result = tokenizer("2dup 12 3i8}".to_string()).unwrap();
+
+        assert_eq!(result[0].node, Token::Ident("2dup".to_string()));
+        assert_eq!(result[1].node, Token::NumericLiteral("12".to_string()));
+        assert_eq!(result[2].node, Token::NumericLiteral("3i8".to_string()));
+    }
+
     #[test]
     fn exp_0() {
         let program = "@main { \"Hello world!\\n\" . }";
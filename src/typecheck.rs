@@ -0,0 +1,1170 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::{
+    ast::{ASTNode, TypeComponent, TypingASTNode},
+    builtins::Builtin,
+    diagnostic::Diagnostic,
+    namemap::{Clause, NameMap, NameMapNode},
+    span::{Span, Spanned},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Named(String),
+    Rigid(String), // Type variable of the signature being checked
+    Var(usize),
+    Quote(Box<Effect>),
+    Ptr(Box<Ty>),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Effect {
+    pub pops: Vec<Ty>,   // Deepest first
+    pub pushes: Vec<Ty>, // Deepest first
+    pub constraints: Vec<(usize, String)>,
+
+    pub consumes_all: bool, // -*
+    pub pushes_many: bool,  // +a*
+    pub never: bool,        // !
+}
+
+impl Effect {
+    // The effect of a quotation whose body is not known
+    pub fn unknown() -> Self {
+        Effect {
+            consumes_all: true,
+            pushes_many: true,
+            ..Effect::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TypeError {
+    pub word: String,
+    pub message: String,
+    pub span: Span,
+}
+
+impl TypeError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.span, self.to_string())
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in word {}: {}", self.word, self.message)
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+// Integers can be of any width, I256 and U1 included
+fn is_numeric(ty: &str) -> bool {
+    match ty {
+        "Int" | "Uint" | "F32" | "F64" => true,
+        _ => ty
+            .strip_prefix('I')
+            .or_else(|| ty.strip_prefix('U'))
+            .and_then(|x| x.parse::<u16>().ok())
+            .is_some_and(|x| x > 0),
+    }
+}
+
+const TRAITS: [&str; 9] = [
+    "Add",
+    "Sub",
+    "Mul",
+    "Div",
+    "Eq",
+    "Ord",
+    "Callable",
+    "Writeable",
+    "Index",
+];
+
+fn implements(ty: &str, tr: &str) -> bool {
+    match tr {
+        "Index" => is_numeric(ty) && !ty.starts_with('F'),
+        "Add" | "Sub" | "Mul" | "Div" | "Ord" => is_numeric(ty),
+        "Eq" | "Writeable" => is_numeric(ty) || ty == "Bool" || ty == "Str",
+        _ => false,
+    }
+}
+
+#[derive(Debug, Default)]
+struct AbstractStack {
+    items: Vec<Ty>,
+    underflow: Vec<Ty>, // Values popped from beneath an open stack, top first
+
+    open: bool,          // Popping past the bottom infers a new input
+    indeterminate: bool, // The depth of the stack is no longer known
+    diverged: bool,
+}
+
+#[derive(Default)]
+struct Checker<'a> {
+    map: Option<&'a NameMap>,
+
+    subst: HashMap<usize, Ty>,
+    constraints: HashMap<usize, Vec<String>>,
+    rigid_constraints: HashMap<String, Vec<String>>,
+    next_var: usize,
+
+    effects: HashMap<String, Effect>,
+    in_progress: HashSet<String>,
+
+    span: Option<Span>, // Of the innermost node an error came from
+}
+
+impl<'a> Checker<'a> {
+    fn fresh(&mut self) -> Ty {
+        self.next_var += 1;
+        Ty::Var(self.next_var)
+    }
+
+    fn resolve(&self, ty: &Ty) -> Ty {
+        match ty {
+            Ty::Var(id) => match self.subst.get(id) {
+                Some(ty) => self.resolve(ty),
+                None => ty.clone(),
+            },
+            ty => ty.clone(),
+        }
+    }
+
+    fn zonk(&self, ty: &Ty) -> Ty {
+        match self.resolve(ty) {
+            Ty::Quote(effect) => Ty::Quote(Box::new(self.zonk_effect(&effect))),
+            Ty::Ptr(ty) => Ty::Ptr(Box::new(self.zonk(&ty))),
+            ty => ty,
+        }
+    }
+
+    fn zonk_effect(&self, effect: &Effect) -> Effect {
+        let mut constraints = Vec::new();
+        for (id, tr) in effect.constraints.iter() {
+            if let Ty::Var(id) = self.resolve(&Ty::Var(*id)) {
+                constraints.push((id, tr.clone()));
+            }
+        }
+
+        Effect {
+            pops: effect.pops.iter().map(|x| self.zonk(x)).collect(),
+            pushes: effect.pushes.iter().map(|x| self.zonk(x)).collect(),
+            constraints,
+            ..*effect
+        }
+    }
+
+    fn display(&self, ty: &Ty) -> String {
+        match self.resolve(ty) {
+            Ty::Named(x) | Ty::Rigid(x) => x,
+            Ty::Var(id) => format!("?{}", id),
+            Ty::Quote(_) => "Quote".to_string(),
+            Ty::Ptr(ty) => format!("Ptr<{}>", self.display(&ty)),
+        }
+    }
+
+    fn satisfies(&mut self, ty: &Ty, tr: &str) -> Result<(), String> {
+        let ok = match self.resolve(ty) {
+            Ty::Var(id) => {
+                self.constraints.entry(id).or_default().push(tr.to_string());
+                true
+            }
+            Ty::Named(x) => implements(&x, tr),
+            Ty::Rigid(x) => self
+                .rigid_constraints
+                .get(&x)
+                .is_some_and(|x| x.iter().any(|x| x == tr)),
+            Ty::Quote(_) => tr == "Callable",
+            Ty::Ptr(_) => false,
+        };
+
+        if ok {
+            Ok(())
+        } else {
+            Err(format!("{} does not implement {}", self.display(ty), tr))
+        }
+    }
+
+    fn unify(&mut self, a: &Ty, b: &Ty) -> Result<(), String> {
+        match (self.resolve(a), self.resolve(b)) {
+            (Ty::Var(a), Ty::Var(b)) if a == b => Ok(()),
+            (Ty::Var(id), other) | (other, Ty::Var(id)) => {
+                self.subst.insert(id, other.clone());
+                for tr in self.constraints.remove(&id).unwrap_or_default() {
+                    self.satisfies(&other, &tr)?;
+                }
+                Ok(())
+            }
+            (Ty::Quote(a), Ty::Quote(b)) => {
+                if a.pops.len() != b.pops.len()
+                    || a.pushes.len() != b.pushes.len()
+                    || a.consumes_all != b.consumes_all
+                    || a.pushes_many != b.pushes_many
+                {
+                    return Err("quotations have different stack effects".to_string());
+                }
+
+                let pops = a.pops.iter().zip(b.pops.iter());
+                for (a, b) in pops.chain(a.pushes.iter().zip(b.pushes.iter())) {
+                    self.unify(a, b)?;
+                }
+                Ok(())
+            }
+            (Ty::Ptr(a), Ty::Ptr(b)) => self.unify(&a, &b),
+            (a, b) if a == b => Ok(()),
+            (a, b) => Err(format!(
+                "expected {} but found {}",
+                self.display(&b),
+                self.display(&a)
+            )),
+        }
+    }
+
+    fn component_type(
+        &mut self,
+        component: &TypeComponent,
+        variables: &mut HashMap<String, Ty>,
+        constraints: &mut Vec<(usize, String)>,
+    ) -> Ty {
+        if let (None, [name]) = (
+            &component.variable,
+            component.type_name_components.as_slice(),
+        ) {
+            if name == "Ptr" {
+                return Ty::Ptr(Box::new(self.fresh()));
+            }
+            if !TRAITS.contains(&name.as_str()) {
+                return Ty::Named(name.clone());
+            }
+        }
+
+        let ty = match &component.variable {
+            Some(name) => match variables.get(name) {
+                Some(ty) => ty.clone(),
+                None => {
+                    let ty = self.fresh();
+                    variables.insert(name.clone(), ty.clone());
+                    ty
+                }
+            },
+            None => self.fresh(),
+        };
+
+        if let Ty::Var(id) = ty {
+            for tr in component.type_name_components.iter() {
+                constraints.push((id, tr.clone()));
+            }
+        }
+
+        ty
+    }
+
+    // Returns the effect and the type variables by name
+    fn signature_effect(&mut self, signature: &[TypingASTNode]) -> (Effect, HashMap<String, Ty>) {
+        let mut effect = Effect::default();
+        let mut variables = HashMap::new();
+        let mut constraints = Vec::new();
+
+        for node in signature {
+            match node {
+                TypingASTNode::Never => effect.never = true,
+                TypingASTNode::Pop(c) if c.poly && c.variable.is_none() => {
+                    effect.consumes_all = true
+                }
+                TypingASTNode::Pop(c) | TypingASTNode::Match(c, _) => {
+                    let ty = self.component_type(c, &mut variables, &mut constraints);
+                    effect.pops.push(ty)
+                }
+                TypingASTNode::Push(c) if c.poly => {
+                    self.component_type(c, &mut variables, &mut constraints);
+                    effect.pushes_many = true
+                }
+                TypingASTNode::Push(c) => {
+                    let ty = self.component_type(c, &mut variables, &mut constraints);
+                    effect.pushes.push(ty)
+                }
+            }
+        }
+        effect.constraints = constraints;
+
+        (effect, variables)
+    }
+
+    fn instantiate(&mut self, effect: &Effect) -> Effect {
+        fn rename(ty: &Ty, names: &mut HashMap<usize, Ty>, checker: &mut Checker) -> Ty {
+            match ty {
+                Ty::Var(id) => match names.get(id) {
+                    Some(ty) => ty.clone(),
+                    None => {
+                        let ty = checker.fresh();
+                        names.insert(*id, ty.clone());
+                        ty
+                    }
+                },
+                Ty::Ptr(ty) => Ty::Ptr(Box::new(rename(ty, names, checker))),
+                ty => ty.clone(),
+            }
+        }
+
+        let mut names = HashMap::new();
+
+        let pops = effect
+            .pops
+            .iter()
+            .map(|x| rename(x, &mut names, self))
+            .collect();
+        let pushes = effect
+            .pushes
+            .iter()
+            .map(|x| rename(x, &mut names, self))
+            .collect();
+        let constraints = effect
+            .constraints
+            .iter()
+            .filter_map(|(id, tr)| match rename(&Ty::Var(*id), &mut names, self) {
+                Ty::Var(id) => Some((id, tr.clone())),
+                _ => None,
+            })
+            .collect();
+
+        Effect {
+            pops,
+            pushes,
+            constraints,
+            ..*effect
+        }
+    }
+
+    fn pop(&mut self, stack: &mut AbstractStack) -> Result<Ty, String> {
+        if let Some(ty) = stack.items.pop() {
+            Ok(ty)
+        } else if stack.indeterminate || stack.diverged {
+            Ok(self.fresh())
+        } else if stack.open {
+            let ty = self.fresh();
+            stack.underflow.push(ty.clone());
+            Ok(ty)
+        } else {
+            Err("stack underflow".to_string())
+        }
+    }
+
+    fn apply(&mut self, stack: &mut AbstractStack, effect: &Effect) -> Result<(), String> {
+        let effect = self.instantiate(effect);
+
+        for (id, tr) in effect.constraints.iter() {
+            self.constraints.entry(*id).or_default().push(tr.clone());
+        }
+
+        for ty in effect.pops.iter().rev() {
+            let found = self.pop(stack)?;
+            self.unify(&found, ty)?;
+        }
+        if effect.consumes_all {
+            stack.items.clear();
+            stack.indeterminate = true;
+        }
+
+        stack.items.extend(effect.pushes);
+        if effect.pushes_many {
+            stack.indeterminate = true;
+        }
+        if effect.never {
+            stack.diverged = true;
+        }
+
+        Ok(())
+    }
+
+    fn pop_quote(&mut self, stack: &mut AbstractStack) -> Result<Effect, String> {
+        let quote = self.pop(stack)?;
+
+        match self.zonk(&quote) {
+            Ty::Quote(effect) => Ok(*effect),
+            Ty::Var(_) => Ok(Effect::unknown()),
+            ty => {
+                self.satisfies(&ty, "Callable")?;
+                Ok(Effect::unknown())
+            }
+        }
+    }
+
+    fn pop_int(&mut self, stack: &mut AbstractStack) -> Result<(), String> {
+        let ty = self.pop(stack)?;
+        self.unify(&ty, &Ty::Named("Int".to_string()))
+    }
+
+    // Whether the effect leaves `extra` more values than it takes, or an unknown amount
+    fn leaves(effect: &Effect, extra: isize) -> bool {
+        effect.never
+            || effect.consumes_all == effect.pushes_many
+                && (effect.consumes_all
+                    || effect.pushes.len() as isize - effect.pops.len() as isize == extra)
+    }
+
+    // A loop applies its quotation once, the values it leaves must fit where it took them from
+    fn apply_loop(&mut self, stack: &mut AbstractStack, effect: &Effect) -> Result<(), String> {
+        let effect = self.instantiate(effect);
+        for (id, tr) in effect.constraints.iter() {
+            self.constraints.entry(*id).or_default().push(tr.clone());
+        }
+        for (taken, left) in effect.pops.iter().zip(effect.pushes.iter()) {
+            self.unify(left, taken)
+                .map_err(|x| format!("the loop changes a value on the stack: {}", x))?;
+        }
+
+        let effect = self.zonk_effect(&effect);
+        self.apply(stack, &effect)
+    }
+
+    // @, if, else and the loops apply the effect of the quotation they are given
+    fn apply_quote(&mut self, stack: &mut AbstractStack, builtin: Builtin) -> Result<(), String> {
+        let effect = self.pop_quote(stack)?;
+
+        match builtin {
+            Builtin::Call => self.apply(stack, &effect),
+            Builtin::If | Builtin::Else => {
+                let condition = self.pop(stack)?;
+                if !Self::leaves(&effect, 0) {
+                    return Err(format!(
+                        "the quotation given to {} must leave as many values as it takes",
+                        builtin.name()
+                    ));
+                }
+
+                self.apply(stack, &effect)?;
+                stack.items.push(condition);
+
+                Ok(())
+            }
+            Builtin::Times => {
+                self.pop_int(stack)?;
+                if !Self::leaves(&effect, 0) {
+                    return Err("the body of times must leave as many values as it takes".into());
+                }
+
+                self.apply_loop(stack, &effect)
+            }
+            Builtin::For => {
+                self.pop_int(stack)?;
+                self.pop_int(stack)?;
+                if !Self::leaves(&effect, -1) {
+                    return Err(
+                        "the body of for must take the index on top of what it leaves".into(),
+                    );
+                }
+
+                stack.items.push(Ty::Named("Int".to_string()));
+                self.apply_loop(stack, &effect)
+            }
+            Builtin::While => {
+                let condition = self.pop_quote(stack)?;
+                if !Self::leaves(&condition, 1) {
+                    return Err(
+                        "the condition of while must leave a flag on top of what it takes".into(),
+                    );
+                }
+                if !Self::leaves(&effect, 0) {
+                    return Err("the body of while must leave as many values as it takes".into());
+                }
+
+                self.apply_loop(stack, &condition)?;
+                self.pop(stack)?;
+                self.apply_loop(stack, &effect)
+            }
+            _ => unreachable!("{} takes no quotation", builtin.name()),
+        }
+    }
+
+    fn effect_of(&mut self, name: &str) -> Result<Effect, String> {
+        if let Some(effect) = self.effects.get(name) {
+            return Ok(effect.clone());
+        }
+        if self.in_progress.contains(name) {
+            return Err(format!(
+                "recursive word {} requires a ?{} [...] signature",
+                name, name
+            ));
+        }
+
+        let map = self.map.expect("checker without name map");
+        let effect = match map.get(name) {
+            Some(NameMapNode::Word {
+                signature: Some(signature),
+                ..
+            }) => self.signature_effect(signature).0,
+            Some(NameMapNode::Word { implementation, .. }) => {
+                self.in_progress.insert(name.to_string());
+                let effect = self.infer(implementation, HashMap::new());
+                self.in_progress.remove(name);
+                effect?
+            }
+            Some(NameMapNode::Overloaded { clauses, .. }) => {
+                self.signature_effect(&specialize(clauses)[0]).0
+            }
+            Some(NameMapNode::Builtin { signature, .. }) => self.signature_effect(signature).0,
+            Some(NameMapNode::AliasedWord(target, _)) => {
+                self.in_progress.insert(name.to_string());
+                let effect = self.effect_of(target);
+                self.in_progress.remove(name);
+                effect?
+            }
+            Some(NameMapNode::StringConst(_)) => Effect {
+                pushes: vec![Ty::Named("Str".to_string())],
+                ..Effect::default()
+            },
+            Some(NameMapNode::NumericConst(n)) => Effect {
+                pushes: vec![Ty::Named(n.type_name())],
+                ..Effect::default()
+            },
+            None => return Err(format!("unknown word {}", name)),
+        };
+
+        self.effects.insert(name.to_string(), effect.clone());
+        Ok(effect)
+    }
+
+    fn local(&self, locals: &HashMap<String, Ty>, name: &str) -> Result<Ty, String> {
+        match locals.get(name) {
+            Some(ty) => Ok(ty.clone()),
+            None => Err(format!("unknown variable {}", name)),
+        }
+    }
+
+    // The type pointed to by the local pointer `name`
+    fn pointee(&mut self, locals: &HashMap<String, Ty>, name: &str) -> Result<Ty, String> {
+        let pointee = self.fresh();
+        let ty = self.local(locals, name)?;
+        self.unify(&ty, &Ty::Ptr(Box::new(pointee.clone())))
+            .map_err(|x| format!("dereferencing {}: {}", name, x))?;
+
+        Ok(pointee)
+    }
+
+    // Locals are visible to the rest of the body they are declared in and to its quotations
+    fn run(
+        &mut self,
+        stack: &mut AbstractStack,
+        body: &[Spanned<ASTNode>],
+        mut locals: HashMap<String, Ty>,
+    ) -> Result<(), String> {
+        for node in body {
+            let result = self.run_node(stack, &mut locals, &node.node);
+            if result.is_err() && self.span.is_none() {
+                self.span = Some(node.span);
+            }
+            result?;
+        }
+
+        Ok(())
+    }
+
+    fn run_node(
+        &mut self,
+        stack: &mut AbstractStack,
+        locals: &mut HashMap<String, Ty>,
+        node: &ASTNode,
+    ) -> Result<(), String> {
+        match node {
+            ASTNode::NumericLiteral(n) => stack.items.push(Ty::Named(n.type_name())),
+            ASTNode::StringLiteral(_) => stack.items.push(Ty::Named("Str".to_string())),
+            ASTNode::Curly(body) => {
+                let effect = self.infer(body, locals.clone())?;
+                stack.items.push(Ty::Quote(Box::new(effect)))
+            }
+            ASTNode::Square(items) => {
+                let effect = self.infer(items, locals.clone())?;
+                if !effect.pops.is_empty() || effect.consumes_all {
+                    return Err("list literal pops values from outside it".to_string());
+                }
+                stack.items.push(Ty::Named("List".to_string()))
+            }
+            ASTNode::Ident(name) if locals.contains_key(name) => {
+                stack.items.push(locals[name].clone())
+            }
+            ASTNode::Ident(name) => {
+                let map = self.map.expect("checker without name map");
+
+                if let Some(NameMapNode::Builtin {
+                    builtin:
+                        builtin @ (Builtin::Call
+                        | Builtin::If
+                        | Builtin::Else
+                        | Builtin::Times
+                        | Builtin::While
+                        | Builtin::For),
+                    ..
+                }) = map.get(name)
+                {
+                    self.apply_quote(stack, *builtin)
+                } else {
+                    let effect = self.effect_of(name)?;
+                    self.apply(stack, &effect)
+                }
+                .map_err(|x| format!("calling {}: {}", name, x))?
+            }
+            ASTNode::Dec(name) => {
+                let ty = self.pop(stack)?;
+                locals.insert(name.clone(), ty);
+            }
+            ASTNode::DecTyped(name, component) => {
+                let mut constraints = Vec::new();
+                let ty = self.component_type(component, &mut HashMap::new(), &mut constraints);
+                for (id, tr) in constraints {
+                    self.constraints.entry(id).or_default().push(tr);
+                }
+
+                let found = self.pop(stack)?;
+                self.unify(&found, &ty)
+                    .map_err(|x| format!("declaring {}: {}", name, x))?;
+                locals.insert(name.clone(), ty);
+            }
+            ASTNode::Assign(name) => {
+                let ty = self.local(locals, name)?;
+
+                let found = self.pop(stack)?;
+                self.unify(&found, &ty)
+                    .map_err(|x| format!("assigning {}: {}", name, x))?;
+            }
+            ASTNode::DecArraySized(name, _) => {
+                let ty = Ty::Ptr(Box::new(self.fresh()));
+                locals.insert(name.clone(), ty);
+            }
+            ASTNode::DecArrayVariable(name) => {
+                let size = self.pop(stack)?;
+                self.satisfies(&size, "Index")
+                    .map_err(|x| format!("sizing {}: {}", name, x))?;
+
+                let ty = Ty::Ptr(Box::new(self.fresh()));
+                locals.insert(name.clone(), ty);
+            }
+            ASTNode::DecPointer(name) => {
+                let ty = Ty::Ptr(Box::new(self.fresh()));
+                let found = self.pop(stack)?;
+                self.unify(&found, &ty)
+                    .map_err(|x| format!("declaring {}: {}", name, x))?;
+                locals.insert(name.clone(), ty);
+            }
+            ASTNode::Address(name) => {
+                let ty = self.local(locals, name)?;
+                stack.items.push(Ty::Ptr(Box::new(ty)))
+            }
+            ASTNode::ReadAddress(name) => {
+                let ty = self.pointee(locals, name)?;
+                stack.items.push(ty)
+            }
+            ASTNode::ReadIndex(name) => {
+                let ty = self.pointee(locals, name)?;
+                let index = self.pop(stack)?;
+                self.satisfies(&index, "Index")
+                    .map_err(|x| format!("indexing {}: {}", name, x))?;
+                stack.items.push(ty)
+            }
+            ASTNode::PointerAssign(name) | ASTNode::IndexAssign(name) => {
+                let ty = self.pointee(locals, name)?;
+                if let ASTNode::IndexAssign(_) = node {
+                    let index = self.pop(stack)?;
+                    self.satisfies(&index, "Index")
+                        .map_err(|x| format!("indexing {}: {}", name, x))?;
+                }
+
+                let found = self.pop(stack)?;
+                self.unify(&found, &ty)
+                    .map_err(|x| format!("writing through {}: {}", name, x))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn infer(
+        &mut self,
+        body: &[Spanned<ASTNode>],
+        locals: HashMap<String, Ty>,
+    ) -> Result<Effect, String> {
+        let mut stack = AbstractStack {
+            open: true,
+            ..AbstractStack::default()
+        };
+
+        self.run(&mut stack, body, locals)?;
+
+        let mut effect = Effect {
+            pops: stack.underflow.into_iter().rev().collect(),
+            pushes: stack.items,
+            constraints: Vec::new(),
+            consumes_all: stack.indeterminate,
+            pushes_many: stack.indeterminate,
+            never: stack.diverged,
+        };
+        for (id, trs) in self.constraints.iter() {
+            for tr in trs {
+                effect.constraints.push((*id, tr.clone()));
+            }
+        }
+
+        Ok(self.zonk_effect(&effect))
+    }
+
+    // Clauses share the effect of the first one and match literals of the popped type
+    fn check_clause(&mut self, first: &Clause, clause: &Clause) -> Result<(), String> {
+        let (expected, _) = self.signature_effect(&first.signature);
+        let (found, _) = self.signature_effect(&clause.signature);
+
+        if expected.pops.len() != found.pops.len() || expected.pushes.len() != found.pushes.len() {
+            return Err("clauses must have the same stack effect as the first one".to_string());
+        }
+        for (a, b) in expected
+            .pops
+            .iter()
+            .zip(found.pops.iter())
+            .chain(expected.pushes.iter().zip(found.pushes.iter()))
+        {
+            self.unify(a, b)
+                .map_err(|x| format!("clause differs from the first one: {}", x))?;
+        }
+
+        let mut variables = HashMap::new();
+        let mut constraints = Vec::new();
+        for node in clause.signature.iter() {
+            if let TypingASTNode::Match(c, patterns) = node {
+                let ty = self.component_type(c, &mut variables, &mut constraints);
+                for pattern in patterns {
+                    self.unify(&ty, &Ty::Named(pattern.type_name()))
+                        .map_err(|x| format!("pattern {}: {}", pattern.to_string(), x))?;
+                }
+            }
+        }
+        for (id, tr) in constraints {
+            self.satisfies(&Ty::Var(id), &tr)?;
+        }
+
+        Ok(())
+    }
+
+    fn check(
+        &mut self,
+        body: &[Spanned<ASTNode>],
+        signature: &[TypingASTNode],
+    ) -> Result<(), String> {
+        let (effect, variables) = self.signature_effect(signature);
+
+        // The variables of the signature are opaque inside the body
+        for (name, ty) in variables.iter() {
+            if let Ty::Var(id) = ty {
+                self.subst.insert(*id, Ty::Rigid(name.clone()));
+            }
+        }
+        for (id, tr) in effect.constraints.iter() {
+            if let Ty::Rigid(name) = self.resolve(&Ty::Var(*id)) {
+                self.rigid_constraints
+                    .entry(name)
+                    .or_default()
+                    .push(tr.clone());
+            }
+        }
+
+        let mut stack = AbstractStack {
+            items: effect.pops.clone(),
+            indeterminate: effect.consumes_all,
+            ..AbstractStack::default()
+        };
+
+        self.run(&mut stack, body, HashMap::new())?;
+
+        if stack.diverged || effect.never {
+            return Ok(());
+        }
+
+        if !stack.indeterminate && !effect.pushes_many && stack.items.len() != effect.pushes.len() {
+            return Err(format!(
+                "leaves {} value(s) on the stack but its signature declares {}",
+                stack.items.len(),
+                effect.pushes.len()
+            ));
+        }
+
+        for (i, (found, expected)) in stack
+            .items
+            .iter()
+            .rev()
+            .zip(effect.pushes.iter().rev())
+            .enumerate()
+        {
+            self.unify(found, expected)
+                .map_err(|x| format!("at stack position {} from the top: {}", i, x))?;
+        }
+
+        Ok(())
+    }
+}
+
+// A variable popped where some clause matches literals has the type of those literals, in every
+// clause of the word, as only values of that type can reach the clauses after it
+fn specialize(clauses: &[Clause]) -> Vec<Vec<TypingASTNode>> {
+    let mut matched: HashMap<usize, String> = HashMap::new();
+    for clause in clauses {
+        for (depth, patterns) in clause.patterns() {
+            if let [first, rest @ ..] = patterns {
+                if rest.iter().all(|x| x.type_name() == first.type_name()) {
+                    matched.insert(depth, first.type_name());
+                }
+            }
+        }
+    }
+
+    clauses
+        .iter()
+        .map(|clause| {
+            let pops: Vec<&TypeComponent> = clause
+                .signature
+                .iter()
+                .filter_map(|x| match x {
+                    TypingASTNode::Pop(c) if !(c.poly && c.variable.is_none()) => Some(c),
+                    TypingASTNode::Match(c, _) => Some(c),
+                    _ => None,
+                })
+                .collect();
+            let bound: HashMap<&str, &String> = pops
+                .iter()
+                .rev()
+                .enumerate()
+                .filter_map(|(depth, c)| Some((c.variable.as_deref()?, matched.get(&depth)?)))
+                .collect();
+
+            let concrete = |c: &TypeComponent| match c.variable.as_deref().map(|x| bound.get(x)) {
+                Some(Some(ty)) => TypeComponent {
+                    variable: None,
+                    type_name_components: vec![ty.to_string()],
+                    ..c.clone()
+                },
+                _ => c.clone(),
+            };
+            clause
+                .signature
+                .iter()
+                .map(|x| match x {
+                    TypingASTNode::Pop(c) => TypingASTNode::Pop(concrete(c)),
+                    TypingASTNode::Push(c) => TypingASTNode::Push(concrete(c)),
+                    TypingASTNode::Match(c, patterns) => {
+                        TypingASTNode::Match(concrete(c), patterns.clone())
+                    }
+                    TypingASTNode::Never => TypingASTNode::Never,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn check_name_map(map: &NameMap) -> Vec<TypeError> {
+    let mut errors = Vec::new();
+
+    let mut names: Vec<&String> = map.keys().collect();
+    names.sort();
+
+    for name in names {
+        let clauses = match &map[name] {
+            NameMapNode::Word {
+                implementation,
+                signature,
+                span,
+                ..
+            } => vec![(implementation, signature.clone(), None, *span)],
+            NameMapNode::Overloaded { clauses, .. } => clauses
+                .iter()
+                .zip(specialize(clauses))
+                .map(|(x, signature)| {
+                    (
+                        &x.implementation,
+                        Some(signature),
+                        Some((&clauses[0], x)),
+                        x.span,
+                    )
+                })
+                .collect(),
+            _ => continue,
+        };
+
+        for (implementation, signature, clause, span) in clauses {
+            let mut checker = Checker {
+                map: Some(map),
+                ..Checker::default()
+            };
+
+            let result = match (signature, clause) {
+                (Some(signature), Some((first, clause))) => checker
+                    .check_clause(first, clause)
+                    .and_then(|_| checker.check(implementation, &signature)),
+                (Some(signature), None) => checker.check(implementation, &signature),
+                (None, _) => checker.effect_of(name).map(|_| ()),
+            };
+
+            if let Err(message) = result {
+                errors.push(TypeError {
+                    word: name.clone(),
+                    message,
+                    span: checker.span.unwrap_or(span),
+                })
+            }
+        }
+    }
+
+    errors
+}
+
+// Checks a body against the types already on the stack, used by the repl
+pub fn check_expression(
+    map: &NameMap,
+    stack: Vec<Ty>,
+    body: &[Spanned<ASTNode>],
+) -> Result<(), TypeError> {
+    fn max_var(ty: &Ty) -> usize {
+        match ty {
+            Ty::Var(id) => *id,
+            Ty::Ptr(ty) => max_var(ty),
+            _ => 0,
+        }
+    }
+
+    // Variables of the given stack must not clash with fresh ones
+    let mut checker = Checker {
+        map: Some(map),
+        next_var: stack.iter().map(max_var).max().unwrap_or(0),
+        ..Checker::default()
+    };
+    let mut stack = AbstractStack {
+        items: stack,
+        ..AbstractStack::default()
+    };
+
+    checker
+        .run(&mut stack, body, HashMap::new())
+        .map_err(|message| TypeError {
+            word: "<expression>".to_string(),
+            message,
+            span: checker.span.unwrap_or_default(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_name_map;
+
+    fn check(program: &str) -> Vec<String> {
+        let program = crate::tokenizer::tokenizer(program.to_string()).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let program = crate::namemap::extract_name_map(program).unwrap();
+
+        check_name_map(&program)
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn it_accepts_matching_signatures() {
+        let errors = check(
+            "?one [+Int] @one { 1 }
+            ?two [+Int +Str] @two { one \"two\" }
+            ?keep [-a +a] @keep { }
+            @inferred { two ::x keep }",
+        );
+
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_the_prelude() {
+        let errors = check(
+            "?main [+Int +Str] @main {
+                \"s\" len 1 2 over rot nip abs 3 min 4 max 5 mod \"abc\" rot rot substr
+            }
+            ?count [+Int] @count { 0 3 { 1 + } times }",
+        );
+
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_loops() {
+        let errors = check(
+            "?sum [+Int] @sum { 0 0 5 { + } for 3 { 2 * } times }
+            ?grow [+Int] @grow { 1 { dup 100 < } { 2 * } while }
+            ?bad_count [] @bad_count { 3 { 1 } times }
+            ?bad_type [+Int] @bad_type { 0 3 { drop \"s\" } times }
+            ?bad_flag [] @bad_flag { { } { } while }",
+        );
+
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors.iter().all(|x| x.contains("bad")), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_closures() {
+        let errors = check(
+            "?adder [-Int +Callable] @adder { ::n { n + } }
+            ?counted [+Int] @counted { 0 ::count 3 { count 1 + :count } times count }
+            ?bad [] @bad { 1 ::x { x \"s\" + drop } @ }
+            ?swapped [+Int] @swapped { { 1 } ::f { 2 } :f f @ }
+            ?bad_type [+Int] @bad_type { { 1 } ::f { \"a\" } :f f @ 1 + }
+            ?bad_count [+Int] @bad_count { { 1 } ::f { 1 2 } :f f @ }",
+        );
+
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors.iter().all(|x| x.contains("bad")), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_lists() {
+        let errors = check(
+            "?pair [-Int +List] @pair { ::x [x 1 +] }
+            ?first [-List +Int] @first { 0 at }
+            ?total [+Int] @total { [1 \"a\" [2]] size }
+            ?bad_items [+List] @bad_items { [\"a\" 1 +] }
+            ?bad_pop [-Int +List] @bad_pop { [drop] }
+            ?bad_index [+Int] @bad_index { [1] \"0\" at }",
+        );
+
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors.iter().all(|x| x.contains("bad")), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_code_points() {
+        let errors = check(
+            "?first [-Str +U32] @first { 0 nextchar drop }
+            ?count [+Int] @count { \"λx\" charcount }
+            ?lambda [+Bool] @lambda { 'λ' 955u32 = }
+            ?bad_char [+U8] @bad_char { 'λ' }
+            ?bad_str [+Str] @bad_str { 955 charstr }",
+        );
+
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().all(|x| x.contains("bad")), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_wide_integers() {
+        let errors = check(
+            "?square [-U256 +U256] @square { dup * }
+            ?main [+U256 +I128] @main { 1E70u256 square -1i128 }
+            ?bad [+U256] @bad { 1u128 square }",
+        );
+
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("bad"), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_rejects_wrong_count() {
+        let errors = check("?one [+Int] @one { 1 2 }");
+
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn it_rejects_wrong_type() {
+        let errors = check("?one [+Int] @one { \"1\" }");
+
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn it_rejects_rigid_escape() {
+        let errors = check("?id [-a +b] @id { }");
+
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn it_checks_constraints() {
+        let errors = check(
+            "?need [-Add:a +a] @need { }
+            ?good [+Int] @good { 1 need }
+            ?bad [+Str] @bad { \"1\" need }",
+        );
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("bad"), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_builtins() {
+        let errors = check(
+            "?main [] @main { 1 0 = { \"equal\" . } if { \"not equal\" . } else drop }
+            ?twice [+Int] @twice { 1 { 2 + } @ }
+            ?bad [] @bad { 1b { 1 } if drop }",
+        );
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("bad"), "{:?}", errors);
+    }
+
+    #[test]
+    fn it_checks_variables() {
+        let errors = check(
+            "?one [-Int +Int] @one { ::x 2 :x x }
+             ?two [-Int +Int] @two { ::x:I8 x }
+             ?three [-Int] @three { ::x \"s\" :x }
+             ?four [] @four { 1 :x }",
+        );
+
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("in word four"));
+    }
+
+    #[test]
+    fn it_checks_pointers() {
+        let errors = check(
+            "?one [+Int] @one { :#p[2] 1 0 $:p[] 0 $p[] }
+             ?two [-Ptr +Int] @two { :#p 1 $:#p $p }
+             ?three [] @three { 1 ::x \"s\" #x :#p $:#p }
+             ?four [] @four { \"s\" :#p[] }",
+        );
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("in word four"));
+        assert!(errors[1].starts_with("in word three"));
+    }
+
+    #[test]
+    fn it_checks_clauses() {
+        let errors = check(
+            "?fib [-Int@(1 | 2) +Int] @fib { drop 1 }
+             ?fib [-Int +Int] @fib { dup 1 - fib swap 2 - fib + }
+             ?gfib [-a@(1 | 2) +a] @gfib { drop 1 }
+             ?gfib [-a +a] @gfib { dup 1 - gfib swap 2 - gfib + }
+             ?use [+Int] @use { 10 gfib }
+             ?one [-Int@1u8 +Int] @one { }
+             ?two [-Int@1 +Int] @two { } ?two [-Int -Int +Int] @two { + }",
+        );
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("in word one"));
+        assert!(errors[1].starts_with("in word two"));
+    }
+
+    #[test]
+    fn it_rejects_underflow() {
+        let errors = check("?one [-a] @one { ::x ::y }");
+
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn it_locates_errors() {
+        let program = "@main {\n  1 \"a\" +\n}";
+        let program = crate::tokenizer::tokenizer(program.to_string()).unwrap();
+        let program = crate::ast::build_tree(program).unwrap();
+        let program = crate::namemap::extract_name_map(program).unwrap();
+
+        let errors = check_name_map(&program);
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].span.line, errors[0].span.column), (2, 9));
+    }
+}